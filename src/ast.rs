@@ -0,0 +1,272 @@
+//! A public description of a parsed prompt
+//!
+//! The parser's own representation borrows from the prompt and is tied to rendering, so
+//! [`explain`](crate::explain) converts it into these owned nodes, annotating every shibuichi
+//! expansion with what it means and what it currently evaluates to.
+use super::{AdvancedConditional, Conditional, Element, Escape, Extensions, Info, Render};
+use serde::Serialize;
+use std::io;
+
+/// A part of a parsed prompt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Node {
+    /// Literal text
+    Text {
+        /// The text
+        text: String,
+    },
+    /// A zsh expansion that is passed through to zsh unchanged
+    Zsh {
+        /// The expansion as it's output
+        source: String,
+    },
+    /// A shibuichi escape, e.g. `%r`
+    Escape {
+        /// The escape code
+        code: char,
+        /// What the escape expands to
+        meaning: &'static str,
+        /// The current expansion
+        value: String,
+    },
+    /// A directory with prefix substitution, e.g. `%/{:~:$HOME}`
+    Path {
+        /// The current expansion
+        value: String,
+    },
+    /// A conditional, e.g. `%(y.dirty.clean)`
+    Conditional {
+        /// The condition code
+        code: char,
+        /// The number given before the code, if any
+        num: Option<i64>,
+        /// What the condition tests, or `None` for conditions that are left to zsh
+        meaning: Option<&'static str>,
+        /// Whether the condition currently holds, or `None` for conditions that are left to zsh
+        value: Option<bool>,
+        /// Rendered when the condition holds
+        true_branch: Vec<Node>,
+        /// Rendered when the condition doesn't hold
+        false_branch: Vec<Node>,
+    },
+    /// An advanced conditional, e.g. `%(o.git.github.gitlab)`
+    AdvancedConditional {
+        /// The condition code
+        code: char,
+        /// What selects the branch
+        meaning: &'static str,
+        /// The index of the branch that's currently rendered
+        value: usize,
+        /// The branches, indexed by value, with the last used for any larger value
+        branches: Vec<Vec<Node>>,
+    },
+    /// A macro, e.g. `%{@name}`
+    Macro {
+        /// The name of the macro
+        name: String,
+        /// The parsed definition
+        body: Vec<Node>,
+    },
+}
+
+/// What an escape code expands to
+fn escape_meaning(code: char, ext: &Extensions) -> Option<&'static str> {
+    match code {
+        'r' => Some("current git branch"),
+        'p' => Some("commits ahead of the remote"),
+        'q' => Some("commits behind the remote"),
+        'x' => Some("number of stashes"),
+        'J' => Some("current jujutsu change id"),
+        'R' => Some("version control system"),
+        _ => ext.escapes.get(&code).map(|custom| custom.description),
+    }
+}
+
+/// What a conditional code tests
+fn condition_meaning(code: char, num: Option<i64>, ext: &Extensions) -> Option<&'static str> {
+    match (code, num.unwrap_or(0)) {
+        ('G', _) => Some("in a repository"),
+        ('y', _) => Some("repository is dirty"),
+        ('m', _) => Some("repository has modified files"),
+        ('s', _) => Some("repository has staged files"),
+        ('o', _) => Some("remote domain is n"),
+        ('p', _) => Some("at least n commits ahead of the remote"),
+        ('q', _) => Some("at least n commits behind the remote"),
+        ('x', _) => Some("at least n stashes"),
+        ('J', _) => Some("in a jujutsu repository"),
+        ('E', _) => Some("jujutsu change is empty"),
+        ('X', _) => Some("jujutsu change has conflicts"),
+        ('R', _) => Some("version control system is n"),
+        ('k', 0) => Some("status is known"),
+        ('k', 1) => Some("remote is known"),
+        ('k', _) => Some("nothing, always false"),
+        _ => ext.conditionals.get(&code).map(|custom| custom.description),
+    }
+}
+
+/// What selects the branch of an advanced conditional
+fn selector_meaning(code: char, ext: &Extensions) -> &'static str {
+    match code {
+        'o' => "remote domain",
+        'p' => "commits ahead of the remote",
+        'q' => "commits behind the remote",
+        'x' => "number of stashes",
+        'R' => "version control system",
+        code => ext.advanced_conditionals[&code].description,
+    }
+}
+
+/// Render something to a string
+fn rendered(elem: &impl Render, info: &mut impl Info, ext: &Extensions) -> String {
+    let mut out = Vec::new();
+    // NOTE writing to a vector can't fail
+    elem.render(&mut out, info, ext).unwrap();
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Convert parsed elements into nodes, merging adjacent text
+pub(crate) fn nodes(elems: &[Element<'_>], info: &mut impl Info, ext: &Extensions) -> Vec<Node> {
+    let mut result = Vec::new();
+    for elem in elems {
+        let node = match elem {
+            Element::Character(chr) => {
+                if let Some(Node::Text { text }) = result.last_mut() {
+                    text.push(*chr);
+                    continue;
+                }
+                Node::Text {
+                    text: chr.to_string(),
+                }
+            }
+            Element::Escape(esc @ Escape(code)) => match escape_meaning(*code, ext) {
+                Some(meaning) => Node::Escape {
+                    code: *code,
+                    meaning,
+                    value: rendered(esc, info, ext),
+                },
+                None => Node::Zsh {
+                    source: rendered(esc, info, ext),
+                },
+            },
+            Element::PathPrefix(path) => Node::Path {
+                value: rendered(path, info, ext),
+            },
+            Element::Conditional(cond) => conditional(cond, info, ext),
+            Element::AdvancedConditional(cond) => advanced_conditional(cond, info, ext),
+            Element::Macro(mac) => Node::Macro {
+                name: mac.name.to_string(),
+                body: mac
+                    .body
+                    .as_ref()
+                    .map(|body| nodes(body, info, ext))
+                    .unwrap_or_default(),
+            },
+            other => Node::Zsh {
+                source: rendered(other, info, ext),
+            },
+        };
+        result.push(node);
+    }
+    result
+}
+
+fn conditional(cond: &Conditional<'_>, info: &mut impl Info, ext: &Extensions) -> Node {
+    Node::Conditional {
+        code: cond.code,
+        num: cond.num,
+        meaning: condition_meaning(cond.code, cond.num, ext),
+        value: cond.test(info, ext),
+        true_branch: nodes(&cond.true_branch, info, ext),
+        false_branch: nodes(&cond.false_branch, info, ext),
+    }
+}
+
+fn advanced_conditional(
+    cond: &AdvancedConditional<'_>,
+    info: &mut impl Info,
+    ext: &Extensions,
+) -> Node {
+    Node::AdvancedConditional {
+        code: cond.code,
+        meaning: selector_meaning(cond.code, ext),
+        value: cond.index(info, ext),
+        branches: cond
+            .conditions
+            .iter()
+            .map(|condition| nodes(condition, info, ext))
+            .collect(),
+    }
+}
+
+/// Write nodes as an indented tree, one node per line
+///
+/// # Errors
+///
+/// When there are problems writing to `out`.
+pub fn write_tree(nodes: &[Node], out: &mut impl io::Write) -> io::Result<()> {
+    write_indented(nodes, 0, out)
+}
+
+fn write_indented(nodes: &[Node], depth: usize, out: &mut impl io::Write) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    for node in nodes {
+        match node {
+            Node::Text { text } => writeln!(out, "{indent}text {text:?}")?,
+            Node::Zsh { source } => writeln!(out, "{indent}zsh {source:?}")?,
+            Node::Escape {
+                code,
+                meaning,
+                value,
+            } => writeln!(out, "{indent}escape %{code}: {meaning} = {value:?}")?,
+            Node::Path { value } => writeln!(out, "{indent}path = {value:?}")?,
+            Node::Conditional {
+                code,
+                num,
+                meaning,
+                value,
+                true_branch,
+                false_branch,
+            } => {
+                let num = num.map(|num| num.to_string()).unwrap_or_default();
+                match (meaning, value) {
+                    (Some(meaning), Some(value)) => {
+                        writeln!(
+                            out,
+                            "{indent}conditional %{num}({code}: {meaning} = {value}"
+                        )?;
+                    }
+                    _ => writeln!(out, "{indent}conditional %{num}({code}: left to zsh")?,
+                }
+                writeln!(out, "{indent}  true:")?;
+                write_indented(true_branch, depth + 2, out)?;
+                writeln!(out, "{indent}  false:")?;
+                write_indented(false_branch, depth + 2, out)?;
+            }
+            Node::AdvancedConditional {
+                code,
+                meaning,
+                value,
+                branches,
+            } => {
+                writeln!(
+                    out,
+                    "{indent}advanced conditional %({code}: {meaning} = {value}"
+                )?;
+                for (ind, branch) in branches.iter().enumerate() {
+                    if ind + 1 == branches.len() {
+                        writeln!(out, "{indent}  {ind}+:")?;
+                    } else {
+                        writeln!(out, "{indent}  {ind}:")?;
+                    }
+                    write_indented(branch, depth + 2, out)?;
+                }
+            }
+            Node::Macro { name, body } => {
+                writeln!(out, "{indent}macro @{name}")?;
+                write_indented(body, depth + 1, out)?;
+            }
+        }
+    }
+    Ok(())
+}
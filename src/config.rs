@@ -0,0 +1,317 @@
+//! The configuration file
+//!
+//! The configuration is TOML with named prompts and settings that would otherwise be passed on the
+//! command line, e.g.
+//!
+//! ```toml
+//! timeout = 100
+//!
+//! [domains]
+//! "git.example.com" = "gitlab"
+//!
+//! [macros]
+//! branch = "%F{blue}%r%f"
+//!
+//! [prompts]
+//! left = [
+//!     "%/{[expand]:~:$HOME} ",  # the working directory
+//!     "%{@branch} %# ",         # the branch
+//! ]
+//! ```
+//!
+//! Prompts and macros can be a single string, or a list of strings that are concatenated, which
+//! allows commenting parts of long prompts.
+use clap::ValueEnum;
+use serde::Deserialize;
+use shibuichi::git::{Backend, Fsmonitor};
+use shibuichi::Domain;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The default location of the configuration file
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config) if !config.is_empty() => PathBuf::from(config),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("shibuichi").join("config.toml"))
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum DomainName {
+    Git,
+    Github,
+    Gitlab,
+    Bitbucket,
+    Azure,
+}
+
+impl From<DomainName> for Domain {
+    fn from(name: DomainName) -> Self {
+        match name {
+            DomainName::Git => Domain::Git,
+            DomainName::Github => Domain::Github,
+            DomainName::Gitlab => Domain::Gitlab,
+            DomainName::Bitbucket => Domain::BitBucket,
+            DomainName::Azure => Domain::Azure,
+        }
+    }
+}
+
+/// How to read git repositories
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendName {
+    /// libgit2
+    Git2,
+    /// gitoxide
+    Gix,
+    /// The git command line
+    Cli,
+}
+
+impl BackendName {
+    /// The backend, if it was built in
+    pub fn backend(self) -> Result<Backend, String> {
+        match self {
+            #[cfg(feature = "git2")]
+            BackendName::Git2 => Ok(Backend::Git2),
+            #[cfg(feature = "gix")]
+            BackendName::Gix => Ok(Backend::Gix),
+            BackendName::Cli => Ok(Backend::Cli),
+            #[allow(unreachable_patterns)]
+            name => Err(format!(
+                "the {} backend isn't supported by this build",
+                name.to_possible_value().unwrap().get_name()
+            )),
+        }
+    }
+}
+
+/// Where to find out which files changed since status was last computed
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsmonitorName {
+    /// The hook configured as `core.fsmonitor`
+    Hook,
+    /// Watchman
+    Watchman,
+    /// Always check every file
+    None,
+}
+
+impl FsmonitorName {
+    /// The file system monitor, if any
+    pub fn fsmonitor(self) -> Option<Fsmonitor> {
+        match self {
+            FsmonitorName::Hook => Some(Fsmonitor::Hook),
+            FsmonitorName::Watchman => Some(Fsmonitor::Watchman(None)),
+            FsmonitorName::None => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+enum PromptParts {
+    Whole(String),
+    Parts(Vec<String>),
+}
+
+impl PromptParts {
+    fn join(self) -> String {
+        match self {
+            PromptParts::Whole(prompt) => prompt,
+            PromptParts::Parts(parts) => parts.concat(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    timeout: Option<u64>,
+    daemon: Option<bool>,
+    cache: Option<bool>,
+    backend: Option<BackendName>,
+    fsmonitor: Option<FsmonitorName>,
+    domains: BTreeMap<String, DomainName>,
+    macros: BTreeMap<String, PromptParts>,
+    prompts: BTreeMap<String, PromptParts>,
+}
+
+/// A parsed configuration file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Time budget for expensive git queries in milliseconds
+    pub timeout: Option<u64>,
+    /// Whether to use a status daemon
+    pub daemon: Option<bool>,
+    /// Whether to cache git information on disk
+    pub cache: Option<bool>,
+    /// How to read git repositories
+    pub backend: Option<BackendName>,
+    /// Where to find out which files changed
+    pub fsmonitor: Option<FsmonitorName>,
+    /// Domains of remote hosts that aren't recognized automatically
+    pub domains: BTreeMap<String, Domain>,
+    /// Macros that can be used in prompts
+    pub macros: BTreeMap<String, String>,
+    /// Named prompts
+    pub prompts: BTreeMap<String, String>,
+}
+
+/// A problem loading the configuration
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// The file isn't a valid configuration
+    Parse(PathBuf, toml::de::Error),
+    /// A prompt was requested that isn't in the configuration
+    MissingPrompt(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "couldn't read {}: {err}", path.display()),
+            Error::Parse(path, err) => write!(f, "invalid config {}: {err}", path.display()),
+            Error::MissingPrompt(name) => write!(f, "no prompt named \"{name}\" in config"),
+        }
+    }
+}
+
+impl Config {
+    /// Parse a configuration from a string
+    fn parse(raw: &str) -> Result<Self, toml::de::Error> {
+        let raw: RawConfig = toml::from_str(raw)?;
+        Ok(Config {
+            timeout: raw.timeout,
+            daemon: raw.daemon,
+            cache: raw.cache,
+            backend: raw.backend,
+            fsmonitor: raw.fsmonitor,
+            domains: raw
+                .domains
+                .into_iter()
+                .map(|(host, name)| (host, name.into()))
+                .collect(),
+            macros: raw
+                .macros
+                .into_iter()
+                .map(|(name, def)| (name, def.join()))
+                .collect(),
+            prompts: raw
+                .prompts
+                .into_iter()
+                .map(|(name, prompt)| (name, prompt.join()))
+                .collect(),
+        })
+    }
+
+    /// Load the configuration
+    ///
+    /// If `path` is `None` this loads the configuration from the default location, which is
+    /// allowed to be missing.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read or parsed.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(raw) => Config::parse(&raw).map_err(|err| Error::Parse(path, err)),
+            Err(err) if !required && err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(Error::Io(path, err)),
+        }
+    }
+
+    /// Get a named prompt
+    ///
+    /// # Errors
+    ///
+    /// If there's no prompt named `name`.
+    pub fn prompt(&self, name: &str) -> Result<&str, Error> {
+        self.prompts
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| Error::MissingPrompt(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackendName, Config, Error, FsmonitorName};
+    use shibuichi::Domain;
+    use std::fs;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(
+            r#"
+            timeout = 50
+            daemon = false
+            backend = "cli"
+            fsmonitor = "watchman"
+
+            [domains]
+            "git.example.com" = "gitlab"
+
+            [macros]
+            arrow = ["%F{blue}", ">%f"]
+
+            [prompts]
+            left = [
+                "%/{[expand]:~:$HOME} ",  # path
+                "%r %# ",         # branch
+            ]
+            right = '%(x.%x.)'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.timeout, Some(50));
+        assert_eq!(config.daemon, Some(false));
+        assert_eq!(config.cache, None);
+        assert_eq!(config.backend, Some(BackendName::Cli));
+        assert_eq!(config.fsmonitor, Some(FsmonitorName::Watchman));
+        assert_eq!(config.domains["git.example.com"], Domain::Gitlab);
+        assert_eq!(config.macros["arrow"], "%F{blue}>%f");
+        assert_eq!(
+            config.prompt("left").unwrap(),
+            "%/{[expand]:~:$HOME} %r %# "
+        );
+        assert_eq!(config.prompt("right").unwrap(), "%(x.%x.)");
+        assert!(matches!(
+            config.prompt("missing"),
+            Err(Error::MissingPrompt(_))
+        ));
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("[domains]\n\"a.com\" = \"other\"").is_err());
+        assert!(Config::parse("[prompts]\nleft = 1").is_err());
+    }
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert!(matches!(Config::load(Some(&path)), Err(Error::Io(..))));
+        fs::write(&path, "timeout = 5").unwrap();
+        assert_eq!(Config::load(Some(&path)).unwrap().timeout, Some(5));
+        fs::write(&path, "timeout = ").unwrap();
+        assert!(matches!(Config::load(Some(&path)), Err(Error::Parse(..))));
+    }
+}
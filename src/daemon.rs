@@ -0,0 +1,473 @@
+//! A background daemon that keeps git status warm
+//!
+//! The daemon listens on a unix socket, and answers requests for the status of a repository. Every
+//! requested repository is kept open on its own thread, since not every backend can be shared
+//! between threads, and its working directory is watched for changes, so that status only needs
+//! to be recomputed after something actually changed. Every connection is handled on its own
+//! thread too, so a slow repository only holds up the shells waiting on it.
+//!
+//! The socket lives in a directory only the user can access, so that nobody else can answer in
+//! the daemon's place.
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rustix::fs::Mode;
+use rustix::process::{getuid, umask};
+use shibuichi::git::{Backend, Repo};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a client will wait for the daemon to respond
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a repository can go without being queried before it's closed
+// NOTE `Duration::from_hours` is too new to rely on
+#[allow(clippy::duration_suboptimal_units)]
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// The default location of the daemon socket
+pub fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("shibuichi.sock"),
+        _ => {
+            let uid = getuid().as_raw();
+            env::temp_dir()
+                .join(format!("shibuichi-{uid}"))
+                .join("shibuichi.sock")
+        }
+    }
+}
+
+/// Check that only the user can access the directory containing `socket`, creating it if `create`
+fn check_socket_dir(socket: &Path, create: bool) -> io::Result<()> {
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if create {
+        match DirBuilder::new().mode(0o700).create(dir) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => (),
+        }
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    if meta.is_dir() && meta.uid() == getuid().as_raw() && meta.mode() & 0o777 == 0o700 {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory that only you can access",
+                dir.display()
+            ),
+        ))
+    }
+}
+
+/// Ask a running daemon for the status of the repository rooted at `root`
+///
+/// Returns `None` if there's no daemon, or it couldn't answer, so that the caller can fall back to
+/// computing status itself.
+pub fn query(socket: &Path, root: &Path) -> Option<(bool, bool, bool)> {
+    check_socket_dir(socket, false).ok()?;
+    let mut stream = UnixStream::connect(socket).ok()?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT)).ok()?;
+    writeln!(stream, "{}", root.to_str()?).ok()?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).ok()?;
+    parse_response(&line)
+}
+
+fn parse_response(line: &str) -> Option<(bool, bool, bool)> {
+    let mut flags = line.trim_end().split(' ').map(|flag| match flag {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    });
+    let status = (flags.next()??, flags.next()??, flags.next()??);
+    flags.next().is_none().then_some(status)
+}
+
+/// What a file system event could change about the status of a repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Change {
+    /// Nothing status depends on, e.g. a new object, or a lock file
+    Nothing,
+    /// The index was written, which computing status can do itself
+    Index,
+    /// Status could have changed
+    Status,
+}
+
+/// Find what `event` in the repository with the git directory `git_dir` could change
+fn classify(event: &notify::Result<Event>, git_dir: &Path) -> Change {
+    let Ok(event) = event else {
+        return Change::Status;
+    };
+    if matches!(event.kind, EventKind::Access(_)) {
+        return Change::Nothing;
+    }
+    if event.need_rescan() || event.paths.is_empty() {
+        return Change::Status;
+    }
+    event
+        .paths
+        .iter()
+        .map(|path| match path.strip_prefix(git_dir) {
+            Err(_) => Change::Status,
+            Ok(rel) if rel == Path::new("index") => Change::Index,
+            Ok(rel) if rel == Path::new("HEAD") || rel.starts_with("refs") => Change::Status,
+            Ok(_) => Change::Nothing,
+        })
+        .max()
+        .unwrap_or(Change::Nothing)
+}
+
+/// When the index was last written, to tell our own writes from those of other processes
+fn index_stamp(repo: &Repo) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(repo.git_dir().join("index")).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// The state of a repository that lives on its thread
+struct RepoState {
+    repo: Repo,
+    // NOTE the watcher stops when dropped, so we need to hold on to it
+    _watcher: RecommendedWatcher,
+    changed: Arc<AtomicBool>,
+    index_changed: Arc<AtomicBool>,
+    index_stamp: Option<(SystemTime, u64)>,
+    status: Option<(bool, bool, bool)>,
+    computed: Arc<AtomicUsize>,
+}
+
+impl RepoState {
+    fn open(root: &Path, backend: Backend, computed: Arc<AtomicUsize>) -> Option<Self> {
+        let repo = Repo::open(root, backend)?;
+        let changed = Arc::new(AtomicBool::new(false));
+        let index_changed = Arc::new(AtomicBool::new(false));
+        let (flag, index_flag) = (changed.clone(), index_changed.clone());
+        let git_dir = repo.git_dir().to_owned();
+        let mut watcher =
+            notify::recommended_watcher(move |event| match classify(&event, &git_dir) {
+                Change::Nothing => (),
+                Change::Index => index_flag.store(true, Ordering::SeqCst),
+                Change::Status => flag.store(true, Ordering::SeqCst),
+            })
+            .ok()?;
+        watcher.watch(root, RecursiveMode::Recursive).ok()?;
+        Some(RepoState {
+            repo,
+            _watcher: watcher,
+            changed,
+            index_changed,
+            index_stamp: None,
+            status: None,
+            computed,
+        })
+    }
+
+    fn status(&mut self) -> (bool, bool, bool) {
+        let changed = self.changed.swap(false, Ordering::SeqCst);
+        // NOTE computing status can write the index, which shouldn't make the next request
+        // compute it again
+        let index_changed = self.index_changed.swap(false, Ordering::SeqCst)
+            && index_stamp(&self.repo) != self.index_stamp;
+        match self.status {
+            Some(status) if !changed && !index_changed => status,
+            _ => {
+                self.repo.refresh();
+                let status = self.repo.status();
+                self.index_stamp = index_stamp(&self.repo);
+                self.computed.fetch_add(1, Ordering::SeqCst);
+                self.status = Some(status);
+                status
+            }
+        }
+    }
+}
+
+/// A repository kept open on its own thread, which stops when this is dropped
+struct WatchedRepo {
+    requests: mpsc::Sender<mpsc::Sender<(bool, bool, bool)>>,
+    last_used: Instant,
+    /// How many times status was computed
+    #[cfg(test)]
+    computed: Arc<AtomicUsize>,
+}
+
+impl WatchedRepo {
+    fn open(root: &Path, backend: Backend) -> Option<Self> {
+        let (requests, received) = mpsc::channel::<mpsc::Sender<_>>();
+        let (opened, open_result) = mpsc::channel();
+        let computed = Arc::new(AtomicUsize::new(0));
+        let root = root.to_owned();
+        let counter = computed.clone();
+        thread::spawn(move || {
+            let Some(mut state) = RepoState::open(&root, backend, counter) else {
+                return;
+            };
+            // NOTE we compute status after watching so that we can't miss a change
+            state.status();
+            let _ = opened.send(());
+            for reply in received {
+                let _ = reply.send(state.status());
+            }
+        });
+        open_result.recv().ok()?;
+        Some(WatchedRepo {
+            requests,
+            last_used: Instant::now(),
+            #[cfg(test)]
+            computed,
+        })
+    }
+
+    fn status(&mut self) -> Option<(bool, bool, bool)> {
+        self.last_used = Instant::now();
+        let (reply, result) = mpsc::channel();
+        self.requests.send(reply).ok()?;
+        result.recv().ok()
+    }
+}
+
+/// A daemon serving status requests
+#[derive(Clone)]
+pub struct Daemon {
+    repos: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<WatchedRepo>>>>>,
+    backend: Backend,
+}
+
+impl Daemon {
+    /// Create a daemon that reads repositories with `backend`
+    pub fn new(backend: Backend) -> Self {
+        Daemon {
+            repos: Arc::default(),
+            backend,
+        }
+    }
+
+    /// Bind to `socket`, replacing it if it's stale
+    ///
+    /// The directory containing `socket` is created if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// If another daemon is already listening on `socket`, other users can access its directory,
+    /// or it can't be bound.
+    pub fn bind(socket: &Path) -> io::Result<UnixListener> {
+        check_socket_dir(socket, true)?;
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {}", socket.display()),
+            ));
+        }
+        match fs::remove_file(socket) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        // NOTE the socket gets its permissions from the umask, so it's never open to others
+        let mask = umask(Mode::from_raw_mode(0o077));
+        let listener = UnixListener::bind(socket);
+        umask(mask);
+        listener
+    }
+
+    /// Answer requests on `listener` forever
+    ///
+    /// # Errors
+    ///
+    /// If accepting a connection fails.
+    pub fn serve(&self, listener: &UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let daemon = self.clone();
+            // NOTE a misbehaving client shouldn't take down the daemon
+            thread::spawn(move || daemon.handle(stream));
+        }
+        Ok(())
+    }
+
+    /// Get the watched repository at `root`, opening it if it isn't already
+    fn repo(&self, root: PathBuf) -> Option<Arc<Mutex<WatchedRepo>>> {
+        let now = Instant::now();
+        let mut repos = self.repos.lock().unwrap_or_else(PoisonError::into_inner);
+        // NOTE a repository that's busy is in use, so it isn't idle
+        repos.retain(|_, repo| {
+            repo.try_lock().map_or(true, |repo| {
+                now.duration_since(repo.last_used) < IDLE_TIMEOUT
+            })
+        });
+        if let Some(repo) = repos.get(&root) {
+            return Some(repo.clone());
+        }
+        // NOTE opening computes status, which other requests shouldn't wait on
+        drop(repos);
+        let repo = WatchedRepo::open(&root, self.backend)?;
+        let mut repos = self.repos.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(
+            repos
+                .entry(root)
+                .or_insert_with(|| Arc::new(Mutex::new(repo)))
+                .clone(),
+        )
+    }
+
+    fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let root = PathBuf::from(line.trim_end_matches('\n'));
+
+        let status = self
+            .repo(root)
+            .and_then(|repo| repo.lock().unwrap_or_else(PoisonError::into_inner).status());
+        let mut stream = stream;
+        match status {
+            Some((dirty, modified, staged)) => writeln!(
+                stream,
+                "{} {} {}",
+                u8::from(dirty),
+                u8::from(modified),
+                u8::from(staged)
+            ),
+            None => writeln!(stream, "error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, parse_response, query, Change, Daemon};
+    use git2::Repository;
+    use notify::event::{AccessKind, CreateKind, EventKind, ModifyKind};
+    use notify::Event;
+    use shibuichi::git::Backend;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn responses() {
+        assert_eq!(parse_response("1 0 1\n"), Some((true, false, true)));
+        assert_eq!(parse_response("0 0 0"), Some((false, false, false)));
+        assert_eq!(parse_response("error\n"), None);
+        assert_eq!(parse_response("0 0"), None);
+        assert_eq!(parse_response("0 0 0 0"), None);
+    }
+
+    #[test]
+    fn events() {
+        let git_dir = Path::new("/repo/.git");
+        let event = |kind, path: &str| Ok(Event::new(kind).add_path(path.into()));
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert_eq!(
+            classify(&event(modify, "/repo/file"), git_dir),
+            Change::Status
+        );
+        assert_eq!(
+            classify(
+                &event(EventKind::Access(AccessKind::Any), "/repo/file"),
+                git_dir
+            ),
+            Change::Nothing
+        );
+        let create = EventKind::Create(CreateKind::File);
+        assert_eq!(
+            classify(&event(create, "/repo/.git/index.lock"), git_dir),
+            Change::Nothing
+        );
+        assert_eq!(
+            classify(&event(create, "/repo/.git/objects/ab/cdef"), git_dir),
+            Change::Nothing
+        );
+        assert_eq!(
+            classify(&event(modify, "/repo/.git/index"), git_dir),
+            Change::Index
+        );
+        assert_eq!(
+            classify(&event(modify, "/repo/.git/HEAD"), git_dir),
+            Change::Status
+        );
+        assert_eq!(
+            classify(&event(create, "/repo/.git/refs/heads/main"), git_dir),
+            Change::Status
+        );
+        assert_eq!(
+            classify(&Err(notify::Error::generic("lost")), git_dir),
+            Change::Status
+        );
+    }
+
+    #[test]
+    fn private_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(Daemon::bind(&shared.join("daemon.sock")).is_err());
+
+        let socket = dir.path().join("private").join("daemon.sock");
+        let _listener = Daemon::bind(&socket).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(socket.parent().unwrap()), 0o700);
+        assert_eq!(mode(&socket) & 0o077, 0);
+    }
+
+    fn serves_status(backend: Backend) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        Repository::init(&root).unwrap();
+        let socket = dir.path().join("daemon").join("daemon.sock");
+        let listener = Daemon::bind(&socket).unwrap();
+        let daemon = Daemon::new(backend);
+        let server = daemon.clone();
+        thread::spawn(move || server.serve(&listener));
+
+        assert!(Daemon::bind(&socket).is_err());
+        assert_eq!(query(&socket, &dir.path().join("missing")), None);
+        // NOTE clients that never send their request would time out every query if they were
+        // handled one at a time
+        let _idle = [
+            UnixStream::connect(&socket).unwrap(),
+            UnixStream::connect(&socket).unwrap(),
+        ];
+        assert_eq!(query(&socket, &root), Some((false, false, false)));
+        // NOTE without changes status is answered from the cache
+        assert_eq!(query(&socket, &root), Some((false, false, false)));
+        let repo = daemon.repo(root.clone()).unwrap();
+        let computed = repo.lock().unwrap().computed.clone();
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+        fs::write(root.join("new"), "contents").unwrap();
+        let start = Instant::now();
+        let mut status = query(&socket, &root);
+        while status != Some((true, true, false)) && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(50));
+            status = query(&socket, &root);
+        }
+        assert_eq!(status, Some((true, true, false)));
+    }
+
+    #[test]
+    fn serves_default_status() {
+        serves_status(Backend::default());
+    }
+
+    #[test]
+    fn serves_cli_status() {
+        serves_status(Backend::Cli);
+    }
+}
@@ -0,0 +1,142 @@
+//! Custom escapes and conditionals
+//!
+//! Programs using this as a library can add their own codes to prompts, e.g. an escape for the
+//! current kubernetes context, by registering them in [`Extensions`] and parsing prompts with
+//! [`Prompt::parse_with_extensions`](crate::Prompt::parse_with_extensions).
+//!
+//! ```
+//! use shibuichi::extensions::Extensions;
+//! use shibuichi::Prompt;
+//! use std::collections::BTreeMap;
+//! use std::sync::Arc;
+//!
+//! let mut extensions = Extensions::default();
+//! extensions
+//!     .escape('Z', "current time zone", |_| "UTC".to_owned())
+//!     .conditional('Z', "time zone is UTC", |_, _| true);
+//! let prompt =
+//!     Prompt::parse_with_extensions("%(Z.%Z.)", &BTreeMap::new(), Arc::new(extensions));
+//! assert!(prompt.is_ok());
+//! ```
+use super::{Info, ADVANCED_CONDITIONAL_CODES, CONDITIONAL_CODES, ESCAPE_CODES, NUMERIC_CODES};
+use std::collections::BTreeMap;
+use std::fmt;
+
+type EscapeFn = dyn Fn(&mut dyn Info) -> String + Send + Sync;
+type ConditionFn = dyn Fn(&mut dyn Info, i64) -> bool + Send + Sync;
+type SelectorFn = dyn Fn(&mut dyn Info) -> usize + Send + Sync;
+
+/// A registered code
+pub(crate) struct Custom<F: ?Sized> {
+    /// What the code means, for [`explain`](crate::explain)
+    pub(crate) description: &'static str,
+    pub(crate) func: Box<F>,
+}
+
+/// Custom codes that can be used in prompts
+///
+/// Codes can't replace existing ones, and conditional codes are shared by conditionals and
+/// advanced conditionals.
+#[derive(Default)]
+pub struct Extensions {
+    pub(crate) escapes: BTreeMap<char, Custom<EscapeFn>>,
+    pub(crate) conditionals: BTreeMap<char, Custom<ConditionFn>>,
+    pub(crate) advanced_conditionals: BTreeMap<char, Custom<SelectorFn>>,
+}
+
+impl Extensions {
+    /// Register an escape, where `%code` expands to the result of `func`
+    ///
+    /// # Panics
+    ///
+    /// If `code` is already an escape, or could start another expansion.
+    pub fn escape(
+        &mut self,
+        code: char,
+        description: &'static str,
+        func: impl Fn(&mut dyn Info) -> String + Send + Sync + 'static,
+    ) -> &mut Self {
+        assert!(
+            !ESCAPE_CODES.contains(code)
+                && !NUMERIC_CODES.contains(code)
+                && !"(<>{-".contains(code)
+                && !code.is_ascii_digit()
+                && !self.escapes.contains_key(&code),
+            "%{code} is already an escape"
+        );
+        self.escapes.insert(
+            code,
+            Custom {
+                description,
+                func: Box::new(func),
+            },
+        );
+        self
+    }
+
+    /// Register a conditional code, where `%n(code.true-text.false-text)` expands to the true text
+    /// if `func` returns true when called with `n`, which defaults to 0
+    ///
+    /// # Panics
+    ///
+    /// If `code` is already a conditional code.
+    pub fn conditional(
+        &mut self,
+        code: char,
+        description: &'static str,
+        func: impl Fn(&mut dyn Info, i64) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.check_conditional(code);
+        self.conditionals.insert(
+            code,
+            Custom {
+                description,
+                func: Box::new(func),
+            },
+        );
+        self
+    }
+
+    /// Register an advanced conditional code, where `%(code.zero.one.more)` expands to the text
+    /// at the index `func` returns, or the last one if there aren't enough
+    ///
+    /// # Panics
+    ///
+    /// If `code` is already a conditional code.
+    pub fn advanced_conditional(
+        &mut self,
+        code: char,
+        description: &'static str,
+        func: impl Fn(&mut dyn Info) -> usize + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.check_conditional(code);
+        self.advanced_conditionals.insert(
+            code,
+            Custom {
+                description,
+                func: Box::new(func),
+            },
+        );
+        self
+    }
+
+    fn check_conditional(&self, code: char) {
+        assert!(
+            !CONDITIONAL_CODES.contains(code)
+                && !ADVANCED_CONDITIONAL_CODES.contains(code)
+                && !self.conditionals.contains_key(&code)
+                && !self.advanced_conditionals.contains_key(&code),
+            "%({code} is already a conditional"
+        );
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("escapes", &self.escapes.keys())
+            .field("conditionals", &self.conditionals.keys())
+            .field("advanced_conditionals", &self.advanced_conditionals.keys())
+            .finish()
+    }
+}
@@ -0,0 +1,241 @@
+//! Fossil information
+//!
+//! A checkout has a sqlite database at its root, `.fslckout` or `_FOSSIL_` on older versions,
+//! which records the checked out version, the repository it came from, and the state of every
+//! file. The branch is read from the repository's tags. Additions, removals, renames and changes
+//! fossil already noticed are recorded, and other modified files are found by comparing size and
+//! modification time, so a file that was only touched counts as modified until fossil looks at
+//! it. Untracked files aren't looked for.
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The names of the checkout database, newest first
+const CHECKOUT_DBS: [&str; 2] = [".fslckout", "_FOSSIL_"];
+
+/// How long to wait for fossil to release a database
+const BUSY_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn open(path: &Path) -> Option<Connection> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+    db.busy_timeout(BUSY_TIMEOUT).ok()?;
+    Some(db)
+}
+
+/// A fossil checkout
+#[derive(Debug, Clone)]
+pub struct Repo {
+    root: PathBuf,
+    db: PathBuf,
+}
+
+impl Repo {
+    /// Find the checkout containing `dir`
+    #[must_use]
+    pub fn discover(dir: &Path) -> Option<Self> {
+        dir.ancestors().find_map(|root| {
+            let db = CHECKOUT_DBS
+                .iter()
+                .map(|name| root.join(name))
+                .find(|db| db.is_file())?;
+            Some(Repo {
+                root: root.to_owned(),
+                db,
+            })
+        })
+    }
+
+    /// The root of the checkout
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The value of a checkout variable
+    fn var(db: &Connection, name: &str) -> Option<String> {
+        db.query_row("SELECT value FROM vvar WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })
+        .ok()
+    }
+
+    /// The branch of the checked out version
+    ///
+    /// This is `None` if the repository can't be read, e.g. in a new checkout with no versions.
+    #[must_use]
+    pub fn branch(&self) -> Option<String> {
+        let checkout = open(&self.db)?;
+        let version: i64 = Self::var(&checkout, "checkout")?.parse().ok()?;
+        // NOTE the repository is usually an absolute path, but is relative to the checkout if not
+        let repository = open(&self.root.join(Self::var(&checkout, "repository")?))?;
+        repository
+            .query_row(
+                "SELECT value FROM tagxref WHERE rid = ?1 AND tagtype > 0
+                    AND tagid = (SELECT tagid FROM tag WHERE tagname = 'branch')",
+                [version],
+                |row| row.get(0),
+            )
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    /// Whether the checkout is dirty, has modified files, and has staged files
+    ///
+    /// Fossil has no staging area, so every change counts as modified. This is clean if the
+    /// checkout database can't be read.
+    #[must_use]
+    pub fn status(&self) -> (bool, bool, bool) {
+        let dirty = open(&self.db).is_some_and(|db| self.changed(&db).unwrap_or(false));
+        (dirty, dirty, false)
+    }
+
+    fn changed(&self, db: &Connection) -> rusqlite::Result<bool> {
+        // NOTE added files have no artifact yet, and renamed ones remember their original name
+        let recorded = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM vfile
+                WHERE chnged != 0 OR deleted != 0 OR rid = 0 OR origname IS NOT NULL)",
+            [],
+            |row| row.get(0),
+        )?;
+        if recorded {
+            return Ok(true);
+        }
+        let repository = Self::var(db, "repository").and_then(|path| open(&self.root.join(path)));
+        let mut files = db.prepare("SELECT pathname, rid, mtime FROM vfile")?;
+        let mut rows = files.query([])?;
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let rid: i64 = row.get(1)?;
+            let mtime: Option<i64> = row.get(2)?;
+            let size = repository.as_ref().and_then(|repository| {
+                repository
+                    .query_row("SELECT size FROM blob WHERE rid = ?1", [rid], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .ok()
+            });
+            if self.file_changed(&path, size, mtime) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn file_changed(&self, path: &str, size: Option<i64>, mtime: Option<i64>) -> bool {
+        let Ok(meta) = fs::symlink_metadata(self.root.join(path)) else {
+            return true;
+        };
+        if size.is_some_and(|size| u64::try_from(size).ok() != Some(meta.len())) {
+            return true;
+        }
+        // NOTE modification times are recorded in seconds
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .and_then(|time| i64::try_from(time.as_secs()).ok());
+        modified.is_none() || modified != mtime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repo;
+    use rusqlite::{params, Connection};
+    use std::fs;
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+
+    /// Create the parts of a checkout and its repository that are read, checked out on `branch`
+    fn create(root: &Path, name: &str, branch: &str) -> Connection {
+        let repository = root.parent().unwrap().join("repo.fossil");
+        let db = Connection::open(&repository).unwrap();
+        db.execute_batch(
+            "CREATE TABLE blob (rid INTEGER PRIMARY KEY, size INTEGER);
+            CREATE TABLE tag (tagid INTEGER PRIMARY KEY, tagname TEXT);
+            CREATE TABLE tagxref (tagid INTEGER, tagtype INTEGER, rid INTEGER, value TEXT);
+            INSERT INTO tag VALUES (7, 'sym-trunk'), (8, 'branch');
+            INSERT INTO blob VALUES (1, 100), (2, 8);",
+        )
+        .unwrap();
+        db.execute("INSERT INTO tagxref VALUES (8, 2, 1, ?1)", [branch])
+            .unwrap();
+
+        let db = Connection::open(root.join(name)).unwrap();
+        db.execute_batch(
+            "CREATE TABLE vvar (name TEXT PRIMARY KEY, value TEXT);
+            CREATE TABLE vfile (
+                id INTEGER PRIMARY KEY, vid INTEGER, chnged INTEGER, deleted INTEGER,
+                rid INTEGER, mtime INTEGER, pathname TEXT, origname TEXT);
+            INSERT INTO vvar VALUES ('checkout', '1');",
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO vvar VALUES ('repository', ?1)",
+            [repository.to_str().unwrap()],
+        )
+        .unwrap();
+        db
+    }
+
+    /// Check out a file with `contents`, recording it like fossil does
+    fn checkout(root: &Path, db: &Connection, name: &str, contents: &str) {
+        fs::write(root.join(name), contents).unwrap();
+        let mtime = fs::metadata(root.join(name))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        db.execute(
+            "INSERT INTO vfile VALUES (1, 1, 0, 0, 2, ?1, ?2, NULL)",
+            params![i64::try_from(mtime.as_secs()).unwrap(), name],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn checkout_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("checkout");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        assert!(Repo::discover(&root.join("sub")).is_none());
+
+        let db = create(&root, "_FOSSIL_", "trunk");
+        let repo = Repo::discover(&root.join("sub")).unwrap();
+        assert_eq!(repo.root(), root);
+        assert_eq!(repo.branch().as_deref(), Some("trunk"));
+        assert_eq!(repo.status(), (false, false, false));
+
+        checkout(&root, &db, "file", "contents");
+        assert_eq!(repo.status(), (false, false, false));
+        fs::write(root.join("file"), "modified contents").unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        fs::write(root.join("file"), "contents").unwrap();
+        db.execute("UPDATE vfile SET mtime = 0", []).unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        fs::remove_file(root.join("file")).unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+
+        db.execute("DELETE FROM vfile", []).unwrap();
+        db.execute(
+            "INSERT INTO vfile VALUES (1, 1, 0, 0, 0, NULL, 'added', NULL)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+    }
+
+    #[test]
+    fn newer_checkout_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("checkout");
+        fs::create_dir(&root).unwrap();
+        create(&root, ".fslckout", "feature");
+        let repo = Repo::discover(&root).unwrap();
+        assert_eq!(repo.branch().as_deref(), Some("feature"));
+        assert_eq!(repo.status(), (false, false, false));
+    }
+}
@@ -0,0 +1,1346 @@
+//! Git information from libgit2, gitoxide, or the git command line
+//!
+//! [`GitInfo`] implements [`Info`] for a directory, computing everything lazily the first time
+//! it's used. Status and remote information are expensive, so they're computed in background
+//! threads once they're needed or prefetched, and can be given up on after a deadline.
+//!
+//! Repositories are read with libgit2 with the `git2` feature, or gitoxide with the `gix`
+//! feature, and can always be read by running `git`. The [`Backend`] can be picked at runtime.
+//! Directories in a mercurial or jujutsu repository are read with [`hg`] or [`jj`] instead, as are
+//! subversion and fossil repositories with the `svn` and `fossil` modules when the `sqlite`
+//! feature is enabled.
+mod cli;
+mod fsmonitor;
+#[cfg(feature = "gix")]
+mod gitoxide;
+#[cfg(feature = "git2")]
+mod libgit2;
+
+use super::util::ParsedScpUrl;
+#[cfg(feature = "sqlite")]
+use super::{fossil, svn};
+use super::{hg, jj};
+use super::{Domain, Info, Needs, Vcs};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+use url::Url;
+
+pub use fsmonitor::{Fsmonitor, Snapshot};
+
+/// Get the host of a remote url
+fn parse_git_origin(origin: &str) -> Option<String> {
+    // NOTE Url creates an owned copy instead of just referencing the string, so we can't just
+    // return a reference here
+    if let Some(domain) = Url::parse(origin).ok().as_ref().and_then(Url::domain) {
+        Some(domain.to_owned())
+    } else {
+        ParsedScpUrl::parse(origin).map(|url| url.host().to_owned())
+    }
+}
+
+/// Get the domain of a remote url, preferring configured `domains`
+fn remote_domain(url: &str, domains: &BTreeMap<String, Domain>) -> Option<Domain> {
+    let url = parse_git_origin(url)?;
+    if let Some(domain) = domains.get(&url) {
+        return Some(*domain);
+    }
+    let domain = match url.as_ref() {
+        "github.com" => Domain::Github,
+        "gitlab.com" => Domain::Gitlab,
+        "bitbucket.org" => Domain::BitBucket,
+        "dev.azure.com" => Domain::Azure,
+        _ => Domain::Git,
+    };
+    Some(domain)
+}
+
+/// How repositories are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// libgit2, through the `git2` crate
+    #[cfg(feature = "git2")]
+    Git2,
+    /// gitoxide, through the `gix` crate
+    #[cfg(feature = "gix")]
+    Gix,
+    /// Running `git status`, which is slower to start, but supports everything the installed git
+    /// does
+    Cli,
+}
+
+impl Default for Backend {
+    /// libgit2 if it's enabled, otherwise gitoxide if it's enabled, otherwise the command line
+    fn default() -> Self {
+        #[cfg(feature = "git2")]
+        return Backend::Git2;
+        #[cfg(all(feature = "gix", not(feature = "git2")))]
+        return Backend::Gix;
+        #[cfg(not(any(feature = "git2", feature = "gix")))]
+        return Backend::Cli;
+    }
+}
+
+/// Whether `dir` looks like it's in a git repository
+fn in_repo(dir: &Path) -> bool {
+    dir.ancestors().any(|dir| dir.join(".git").exists())
+}
+
+enum Inner {
+    #[cfg(feature = "git2")]
+    Git2(git2::Repository),
+    #[cfg(feature = "gix")]
+    Gix(Box<gix::Repository>),
+    Cli(cli::Repo),
+}
+
+/// A way to open the same repository in another thread
+enum Handle {
+    #[cfg(any(feature = "git2", feature = "gix"))]
+    Path(PathBuf, Backend),
+    // NOTE the command line output is shared, so that git only needs to be run once
+    Cli(cli::Repo),
+}
+
+impl Handle {
+    // NOTE only opening a path can fail, which isn't possible without a library backend
+    #[cfg_attr(
+        not(any(feature = "git2", feature = "gix")),
+        allow(clippy::unnecessary_wraps)
+    )]
+    fn open(self) -> Option<Repo> {
+        match self {
+            #[cfg(any(feature = "git2", feature = "gix"))]
+            Handle::Path(path, backend) => Repo::open(&path, backend),
+            Handle::Cli(repo) => Some(Repo {
+                inner: Inner::Cli(repo),
+            }),
+        }
+    }
+}
+
+/// An open repository
+pub struct Repo {
+    inner: Inner,
+}
+
+impl Repo {
+    /// Find the repository containing `dir`
+    ///
+    /// If `backend` can't read a repository that seems to be there, e.g. because it uses an
+    /// extension only newer versions of git support, this falls back to the command line.
+    #[must_use]
+    pub fn discover(dir: &Path, backend: Backend) -> Option<Self> {
+        let inner = match backend {
+            #[cfg(feature = "git2")]
+            Backend::Git2 => libgit2::discover(dir).map(Inner::Git2),
+            #[cfg(feature = "gix")]
+            Backend::Gix => gitoxide::discover(dir).map(|repo| Inner::Gix(Box::new(repo))),
+            Backend::Cli => None,
+        };
+        let inner = match inner {
+            Some(inner) => inner,
+            None if backend == Backend::Cli || in_repo(dir) => Inner::Cli(cli::discover(dir)?),
+            None => return None,
+        };
+        Some(Repo { inner })
+    }
+
+    /// Open the repository at `path`, which is either the root of its working directory or its
+    /// git directory
+    ///
+    /// This falls back to the command line like [`Repo::discover`].
+    #[must_use]
+    pub fn open(path: &Path, backend: Backend) -> Option<Self> {
+        let inner = match backend {
+            #[cfg(feature = "git2")]
+            Backend::Git2 => libgit2::open(path).map(Inner::Git2),
+            #[cfg(feature = "gix")]
+            Backend::Gix => gitoxide::open(path).map(|repo| Inner::Gix(Box::new(repo))),
+            Backend::Cli => None,
+        };
+        let inner = match inner {
+            Some(inner) => inner,
+            None => Inner::Cli(cli::discover(path)?),
+        };
+        Some(Repo { inner })
+    }
+
+    fn handle(&self) -> Handle {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => Handle::Path(repo.path().to_owned(), Backend::Git2),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => Handle::Path(repo.git_dir().to_owned(), Backend::Gix),
+            Inner::Cli(repo) => Handle::Cli(repo.clone()),
+        }
+    }
+
+    /// The backend reading the repository
+    #[must_use]
+    pub fn backend(&self) -> Backend {
+        match self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(_) => Backend::Git2,
+            #[cfg(feature = "gix")]
+            Inner::Gix(_) => Backend::Gix,
+            Inner::Cli(_) => Backend::Cli,
+        }
+    }
+
+    /// The git directory, e.g. `.git`
+    #[must_use]
+    pub fn git_dir(&self) -> &Path {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => repo.path(),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => repo.git_dir(),
+            Inner::Cli(repo) => repo.git_dir(),
+        }
+    }
+
+    /// The root of the working directory, unless the repository is bare
+    #[must_use]
+    pub fn workdir(&self) -> Option<&Path> {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => repo.workdir(),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => repo.workdir(),
+            Inner::Cli(repo) => repo.workdir(),
+        }
+    }
+
+    /// The short name of the current branch, or `HEAD` if it's detached
+    #[must_use]
+    pub fn branch(&self) -> Option<String> {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::branch(repo),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::branch(repo),
+            Inner::Cli(repo) => cli::branch(repo),
+        }
+    }
+
+    /// The id of the commit HEAD points to
+    #[must_use]
+    pub fn head_id(&self) -> Option<String> {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::head_id(repo),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::head_id(repo),
+            Inner::Cli(repo) => cli::head_id(repo),
+        }
+    }
+
+    /// The id of the commit the upstream of the current branch points to
+    #[must_use]
+    pub fn upstream_id(&self) -> Option<String> {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::upstream_id(repo),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::upstream_id(repo),
+            Inner::Cli(repo) => cli::upstream_id(repo),
+        }
+    }
+
+    /// Whether the repository is dirty, has modified files, and has staged files
+    #[must_use]
+    pub fn status(&self) -> (bool, bool, bool) {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::status(repo),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::status(repo),
+            Inner::Cli(repo) => cli::status(repo),
+        }
+    }
+
+    /// Like [`Repo::status`], but only check the files `fsmonitor` says changed since `snapshot`
+    /// was taken, along with the ones that were modified then
+    ///
+    /// This also returns the snapshot to pass next time. Only libgit2 updates status
+    /// incrementally, other backends compute it fully and return no snapshot, although git uses
+    /// `core.fsmonitor` itself with the command line.
+    #[must_use]
+    #[cfg_attr(not(feature = "git2"), allow(unused_variables))]
+    pub fn status_since(
+        &self,
+        fsmonitor: &Fsmonitor,
+        snapshot: Option<&Snapshot>,
+    ) -> ((bool, bool, bool), Option<Snapshot>) {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::status_since(repo, fsmonitor, snapshot),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => (gitoxide::status(repo), None),
+            Inner::Cli(repo) => (cli::status(repo), None),
+        }
+    }
+
+    /// The domain of the remote of the upstream branch, and the number of commits the current
+    /// branch is ahead and behind it
+    ///
+    /// `domains` maps hosts to domains for hosts that aren't recognized automatically.
+    #[must_use]
+    pub fn remote_info(
+        &self,
+        domains: &BTreeMap<String, Domain>,
+    ) -> Option<(Domain, usize, usize)> {
+        match &self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::remote_info(repo, domains),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::remote_info(repo, domains),
+            Inner::Cli(repo) => cli::remote_info(repo, domains),
+        }
+    }
+
+    /// Forget anything read from the repository, so that it's read again
+    ///
+    /// Only the command line backend remembers anything, so that git is only run once.
+    pub fn refresh(&mut self) {
+        match &mut self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(_) => (),
+            #[cfg(feature = "gix")]
+            Inner::Gix(_) => (),
+            Inner::Cli(repo) => cli::refresh(repo),
+        }
+    }
+
+    /// The number of stashes
+    pub fn stashes(&mut self) -> usize {
+        match &mut self.inner {
+            #[cfg(feature = "git2")]
+            Inner::Git2(repo) => libgit2::stashes(repo),
+            #[cfg(feature = "gix")]
+            Inner::Gix(repo) => gitoxide::stashes(repo),
+            Inner::Cli(repo) => cli::stashes(repo),
+        }
+    }
+}
+
+/// Find the jujutsu, mercurial, subversion, or fossil repository containing `dir`, unless there's
+/// a git repository inside it
+fn discover_other(dir: &Path) -> Option<OtherRepo> {
+    let markers = [
+        ".jj",
+        ".hg",
+        #[cfg(feature = "sqlite")]
+        ".svn/wc.db",
+        #[cfg(feature = "sqlite")]
+        ".fslckout",
+        #[cfg(feature = "sqlite")]
+        "_FOSSIL_",
+        ".git",
+    ];
+    let root = dir
+        .ancestors()
+        .find(|dir| markers.iter().any(|marker| dir.join(marker).exists()))?;
+    // NOTE if git is in the same directory it's colocated with jujutsu, or a mirror of mercurial
+    if root.join(".jj").is_dir() {
+        jj::Repo::discover(root).map(OtherRepo::Jj)
+    } else if root.join(".hg").is_dir() {
+        hg::Repo::discover(root).map(OtherRepo::Hg)
+    } else {
+        discover_sqlite(root)
+    }
+}
+
+/// Find the subversion or fossil repository at `root`
+#[cfg(feature = "sqlite")]
+fn discover_sqlite(root: &Path) -> Option<OtherRepo> {
+    if root.join(".svn").join("wc.db").is_file() {
+        svn::Repo::discover(root).map(OtherRepo::Svn)
+    } else {
+        // NOTE an outer checkout doesn't count when git is found first
+        fossil::Repo::discover(root)
+            .filter(|repo| repo.root() == root)
+            .map(OtherRepo::Fossil)
+    }
+}
+
+/// Without sqlite subversion and fossil repositories can't be read
+#[cfg(not(feature = "sqlite"))]
+fn discover_sqlite(_root: &Path) -> Option<OtherRepo> {
+    None
+}
+
+/// A repository of another version control system
+#[derive(Clone)]
+enum OtherRepo {
+    Hg(hg::Repo),
+    Jj(jj::Repo),
+    #[cfg(feature = "sqlite")]
+    Svn(svn::Repo),
+    #[cfg(feature = "sqlite")]
+    Fossil(fossil::Repo),
+}
+
+impl OtherRepo {
+    fn root(&self) -> &Path {
+        match self {
+            OtherRepo::Hg(repo) => repo.root(),
+            OtherRepo::Jj(repo) => repo.root(),
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Svn(repo) => repo.root(),
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Fossil(repo) => repo.root(),
+        }
+    }
+
+    /// The current branch, which jujutsu doesn't have
+    fn branch(&self) -> Option<String> {
+        match self {
+            OtherRepo::Hg(repo) => Some(repo.branch()),
+            OtherRepo::Jj(_) => None,
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Svn(repo) => repo.branch(),
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Fossil(repo) => repo.branch(),
+        }
+    }
+
+    fn status(&self) -> (bool, bool, bool) {
+        match self {
+            OtherRepo::Hg(repo) => repo.status(),
+            OtherRepo::Jj(repo) => repo.status(),
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Svn(repo) => repo.status(),
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Fossil(repo) => repo.status(),
+        }
+    }
+
+    fn stashes(&self) -> usize {
+        match self {
+            // NOTE shelves are mercurial's stashes
+            OtherRepo::Hg(repo) => repo.shelves(),
+            OtherRepo::Jj(_) => 0,
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Svn(_) | OtherRepo::Fossil(_) => 0,
+        }
+    }
+
+    fn vcs(&self) -> Vcs {
+        match self {
+            OtherRepo::Hg(_) => Vcs::Hg,
+            OtherRepo::Jj(_) => Vcs::Jj,
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Svn(_) => Vcs::Svn,
+            #[cfg(feature = "sqlite")]
+            OtherRepo::Fossil(_) => Vcs::Fossil,
+        }
+    }
+}
+
+#[derive(Default)]
+enum CachedRepo {
+    #[default]
+    Unknown,
+    NoRepo,
+    Repo(Repo),
+    Other(OtherRepo),
+}
+
+impl CachedRepo {
+    fn load(&mut self, dir: &Path, backend: Backend) -> &mut Self {
+        if let CachedRepo::Unknown = self {
+            *self = if let Some(repo) = discover_other(dir) {
+                CachedRepo::Other(repo)
+            } else if let Some(repo) = Repo::discover(dir, backend) {
+                CachedRepo::Repo(repo)
+            } else {
+                CachedRepo::NoRepo
+            };
+        }
+        self
+    }
+
+    /// Get the git repository, if that's what `dir` is in
+    fn get(&mut self, dir: &Path, backend: Backend) -> Option<&mut Repo> {
+        match self.load(dir, backend) {
+            CachedRepo::Repo(repo) => Some(repo),
+            _ => None,
+        }
+    }
+}
+
+/// A lazily computed value that might not be known
+#[derive(Default)]
+enum Lazy<T> {
+    #[default]
+    Uncomputed,
+    Pending(mpsc::Receiver<T>),
+    Unknown,
+    Known(T),
+}
+
+impl<T> Lazy<T> {
+    /// Start computing `func` on the repository in another thread
+    ///
+    /// If there's no repository this is `default`.
+    fn spawn(
+        repo: Option<&Repo>,
+        default: T,
+        func: impl FnOnce(&Repo) -> T + Send + 'static,
+    ) -> Self
+    where
+        T: Send + 'static,
+    {
+        let Some(repo) = repo else {
+            return Lazy::Known(default);
+        };
+        // NOTE repositories can't be shared across threads, so the thread opens its own
+        let handle = repo.handle();
+        Lazy::spawn_with(move || handle.open().map(|repo| func(&repo)))
+    }
+
+    /// Start computing `func` in another thread, which is unknown if it returns `None`
+    fn spawn_with(func: impl FnOnce() -> Option<T> + Send + 'static) -> Self
+    where
+        T: Send + 'static,
+    {
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            if let Some(val) = func() {
+                // NOTE if we already gave up the receiver is gone, which is fine
+                let _ = send.send(val);
+            }
+        });
+        Lazy::Pending(recv)
+    }
+
+    /// Get the value only if it's already known
+    fn peek(&self) -> Option<&T> {
+        match self {
+            Lazy::Known(val) => Some(val),
+            Lazy::Uncomputed | Lazy::Pending(_) | Lazy::Unknown => None,
+        }
+    }
+
+    /// Wait for a pending value, giving up at `deadline`
+    fn wait(&mut self, deadline: Option<Instant>) -> Option<&T> {
+        if let Lazy::Pending(recv) = self {
+            let res = match deadline {
+                Some(deadline) => recv
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok(),
+                None => recv.recv().ok(),
+            };
+            *self = res.map_or(Lazy::Unknown, Lazy::Known);
+        }
+        self.peek()
+    }
+}
+
+type StatusSource = Arc<dyn Fn(&Path) -> Option<(bool, bool, bool)> + Send + Sync>;
+
+/// [`Info`] for the repository containing a directory
+///
+/// The repository can be any of:
+///
+/// - git, read with the configured [`Backend`]
+/// - mercurial, with the active bookmark or branch, and shelves for stashes
+/// - jujutsu, even colocated with git, with the bookmarks on the current change as the branch
+/// - subversion, with the repository path of the working copy, e.g. `trunk`, as the branch
+/// - fossil, with the branch of the checked out version
+///
+/// Subversion and fossil need the `sqlite` feature. [`Info::git_exists`] is true, and branch and
+/// status are filled in, for every kind of repository, see [`Info::vcs`] to tell them apart. The
+/// remote domain and the commits ahead and behind, `%p`, `%q`, `o`, `p` and `q`, are only ever
+/// known for git, and stashes, `%x` and `x`, only for git and mercurial. The jujutsu change codes
+/// `%J`, `J`, `E` and `X` are only set for jujutsu.
+///
+/// ```no_run
+/// use shibuichi::GitInfo;
+///
+/// let mut info = GitInfo::new("/path/to/repo");
+/// shibuichi::expand("%r%(y.*.)", &mut info, &mut std::io::stdout()).unwrap();
+/// ```
+#[derive(Default)]
+pub struct GitInfo {
+    dir: PathBuf,
+    repo: CachedRepo,
+    remote_info: Lazy<(Domain, usize, usize)>,
+    branch: Option<String>,
+    stashes: Option<usize>,
+    status: Lazy<((bool, bool, bool), Option<Snapshot>)>,
+    change: Lazy<jj::Change>,
+    fsmonitor: Option<Fsmonitor>,
+    snapshot: Option<Snapshot>,
+    named_dirs: Vec<(String, PathBuf)>,
+    deadline: Option<Instant>,
+    fast: bool,
+    domains: BTreeMap<String, Domain>,
+    status_source: Option<StatusSource>,
+    backend: Backend,
+}
+
+impl GitInfo {
+    /// Get information for `dir`, which is also the current path
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        GitInfo {
+            dir: dir.into(),
+            ..GitInfo::default()
+        }
+    }
+
+    /// Read the repository with `backend` instead of the default
+    #[must_use]
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Use domains for remote hosts that aren't recognized automatically
+    #[must_use]
+    pub fn with_domains(mut self, domains: BTreeMap<String, Domain>) -> Self {
+        self.domains = domains;
+        self
+    }
+
+    /// Abbreviate paths with zsh style named directories, see [`parse_named_dirs`](crate::util::parse_named_dirs)
+    #[must_use]
+    pub fn with_named_dirs(mut self, named_dirs: Vec<(String, PathBuf)>) -> Self {
+        self.named_dirs = named_dirs;
+        self
+    }
+
+    /// Give up on status and remote information that isn't computed by `deadline`
+    ///
+    /// Information that was given up on is unknown, see [`Info::git_status_known`].
+    #[must_use]
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Try getting the status of a repository from `source` before computing it
+    ///
+    /// `source` is called with the root of the working directory in a background thread, e.g. to
+    /// ask a daemon that already knows it.
+    #[must_use]
+    pub fn with_status_source(
+        mut self,
+        source: impl Fn(&Path) -> Option<(bool, bool, bool)> + Send + Sync + 'static,
+    ) -> Self {
+        self.status_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Only check the files `fsmonitor` says changed when computing status
+    ///
+    /// This needs a snapshot from a previous status, see [`GitInfo::set_snapshot`], otherwise it
+    /// only takes one.
+    #[must_use]
+    pub fn with_fsmonitor(mut self, fsmonitor: Fsmonitor) -> Self {
+        self.fsmonitor = Some(fsmonitor);
+        self
+    }
+
+    /// Only use status and remote information that's already computed, instead of waiting
+    pub fn set_fast(&mut self, fast: bool) {
+        self.fast = fast;
+    }
+
+    /// Get the repository, if any
+    pub fn repository(&mut self) -> Option<&Repo> {
+        self.repo.get(&self.dir, self.backend).map(|repo| &*repo)
+    }
+
+    /// Use a known remote domain, and commits ahead and behind, e.g. from a cache
+    ///
+    /// This does nothing if remote information is already being computed.
+    pub fn set_remote_info(&mut self, remote_info: (Domain, usize, usize)) {
+        if let Lazy::Uncomputed = self.remote_info {
+            self.remote_info = Lazy::Known(remote_info);
+        }
+    }
+
+    /// Use a known number of stashes, e.g. from a cache
+    pub fn set_stashes(&mut self, stashes: usize) {
+        self.stashes.get_or_insert(stashes);
+    }
+
+    /// Use the snapshot status was last computed from, e.g. from a cache
+    ///
+    /// This does nothing if status is already being computed.
+    pub fn set_snapshot(&mut self, snapshot: Snapshot) {
+        if let Lazy::Uncomputed = self.status {
+            self.snapshot = Some(snapshot);
+        }
+    }
+
+    /// Whether status has been requested or prefetched
+    #[must_use]
+    pub fn status_started(&self) -> bool {
+        !matches!(self.status, Lazy::Uncomputed)
+    }
+
+    /// The remote information if it's been computed, without waiting
+    pub fn computed_remote_info(&mut self) -> Option<(Domain, usize, usize)> {
+        self.remote_info.wait(Some(Instant::now())).copied()
+    }
+
+    /// Whether the repository is dirty, has modified files, and has staged files, if that's been
+    /// computed, without waiting
+    pub fn computed_status(&mut self) -> Option<(bool, bool, bool)> {
+        self.status
+            .wait(Some(Instant::now()))
+            .map(|status| status.0)
+    }
+
+    /// The snapshot status was computed from, if it's been computed with a file system monitor,
+    /// without waiting
+    pub fn computed_snapshot(&mut self) -> Option<Snapshot> {
+        self.status.wait(Some(Instant::now()))?.1.clone()
+    }
+
+    /// The number of stashes if it's been computed
+    #[must_use]
+    pub fn computed_stashes(&self) -> Option<usize> {
+        self.stashes
+    }
+
+    fn start_remote_info(&mut self) {
+        if let Lazy::Uncomputed = self.remote_info {
+            let domains = self.domains.clone();
+            let repo = self.repo.get(&self.dir, self.backend).map(|repo| &*repo);
+            self.remote_info = Lazy::spawn(repo, (Domain::Git, 0, 0), move |repo| {
+                repo.remote_info(&domains).unwrap_or((Domain::Git, 0, 0))
+            });
+        }
+    }
+
+    fn start_change(&mut self) {
+        if let (CachedRepo::Other(OtherRepo::Jj(repo)), Lazy::Uncomputed) =
+            (self.repo.load(&self.dir, self.backend), &self.change)
+        {
+            let repo = repo.clone();
+            self.change = Lazy::spawn_with(move || repo.change().cloned());
+        }
+    }
+
+    fn start_status(&mut self) {
+        if let Lazy::Uncomputed = self.status {
+            // NOTE other version control systems compute status without git's options
+            if let CachedRepo::Other(repo) = self.repo.load(&self.dir, self.backend) {
+                let repo = repo.clone();
+                self.status = Lazy::spawn_with(move || Some((repo.status(), None)));
+            }
+        }
+        if let Lazy::Uncomputed = self.status {
+            let source = self.status_source.clone();
+            let fsmonitor = self.fsmonitor.clone();
+            let snapshot = self.snapshot.take();
+            let repo = self.repo.get(&self.dir, self.backend).map(|repo| &*repo);
+            let root = repo.and_then(Repo::workdir).map(Path::to_owned);
+            self.status = Lazy::spawn(repo, ((false, false, false), None), move |repo| {
+                let from_source = match (source, root) {
+                    (Some(source), Some(root)) => source(&root),
+                    _ => None,
+                };
+                match (from_source, fsmonitor) {
+                    (Some(status), _) => (status, None),
+                    (None, Some(fsmonitor)) => repo.status_since(&fsmonitor, snapshot.as_ref()),
+                    (None, None) => (repo.status(), None),
+                }
+            });
+        }
+    }
+
+    fn git_remote_info(&mut self) -> Option<&(Domain, usize, usize)> {
+        self.start_remote_info();
+        if self.fast {
+            self.remote_info.peek()
+        } else {
+            self.remote_info.wait(self.deadline)
+        }
+    }
+
+    fn jj_change(&mut self) -> Option<&jj::Change> {
+        self.start_change();
+        if self.fast {
+            self.change.peek()
+        } else {
+            self.change.wait(self.deadline)
+        }
+    }
+
+    fn git_status(&mut self) -> Option<&(bool, bool, bool)> {
+        self.start_status();
+        let status = if self.fast {
+            self.status.peek()
+        } else {
+            self.status.wait(self.deadline)
+        };
+        status.map(|status| &status.0)
+    }
+}
+
+impl Info for GitInfo {
+    fn current_path(&mut self) -> &Path {
+        &self.dir
+    }
+
+    fn git_exists(&mut self) -> bool {
+        !matches!(self.repo.load(&self.dir, self.backend), CachedRepo::NoRepo)
+    }
+
+    fn git_dirty(&mut self) -> bool {
+        self.git_status().is_some_and(|status| status.0)
+    }
+
+    fn git_modified(&mut self) -> bool {
+        self.git_status().is_some_and(|status| status.1)
+    }
+
+    fn git_staged(&mut self) -> bool {
+        self.git_status().is_some_and(|status| status.2)
+    }
+
+    fn git_status_known(&mut self) -> bool {
+        self.git_status().is_some()
+    }
+
+    fn git_remote_domain(&mut self) -> Domain {
+        self.git_remote_info().map_or(Domain::Git, |info| info.0)
+    }
+
+    fn git_remote_ahead(&mut self) -> usize {
+        self.git_remote_info().map_or(0, |info| info.1)
+    }
+
+    fn git_remote_behind(&mut self) -> usize {
+        self.git_remote_info().map_or(0, |info| info.2)
+    }
+
+    fn git_remote_known(&mut self) -> bool {
+        self.git_remote_info().is_some()
+    }
+
+    fn git_branch(&mut self) -> &str {
+        // NOTE jujutsu has no current branch, and git sees a detached HEAD
+        if let CachedRepo::Other(OtherRepo::Jj(_)) = self.repo.load(&self.dir, self.backend) {
+            return self
+                .jj_change()
+                .map_or("", |change| change.bookmarks.as_str());
+        }
+        if self.branch.is_none() {
+            let branch = match self.repo.load(&self.dir, self.backend) {
+                CachedRepo::Repo(repo) => repo.branch(),
+                CachedRepo::Other(repo) => repo.branch(),
+                CachedRepo::Unknown | CachedRepo::NoRepo => None,
+            };
+            self.branch = Some(branch.unwrap_or_default());
+        }
+        self.branch.as_deref().unwrap()
+    }
+
+    fn git_root(&mut self) -> Option<&Path> {
+        match self.repo.load(&self.dir, self.backend) {
+            CachedRepo::Repo(repo) => repo.workdir(),
+            CachedRepo::Other(repo) => Some(repo.root()),
+            CachedRepo::Unknown | CachedRepo::NoRepo => None,
+        }
+    }
+
+    fn git_stashes(&mut self) -> usize {
+        if self.stashes.is_none() {
+            self.stashes = Some(match self.repo.load(&self.dir, self.backend) {
+                CachedRepo::Repo(repo) => repo.stashes(),
+                CachedRepo::Other(repo) => repo.stashes(),
+                CachedRepo::Unknown | CachedRepo::NoRepo => 0,
+            });
+        }
+        self.stashes.unwrap()
+    }
+
+    fn vcs(&mut self) -> Vcs {
+        match self.repo.load(&self.dir, self.backend) {
+            CachedRepo::Unknown | CachedRepo::NoRepo => Vcs::None,
+            CachedRepo::Repo(_) => Vcs::Git,
+            CachedRepo::Other(repo) => repo.vcs(),
+        }
+    }
+
+    fn jj_exists(&mut self) -> bool {
+        self.vcs() == Vcs::Jj
+    }
+
+    fn jj_change_id(&mut self) -> &str {
+        self.jj_change()
+            .map_or("", |change| change.change_id.as_str())
+    }
+
+    fn jj_empty(&mut self) -> bool {
+        self.jj_change().is_some_and(|change| change.empty)
+    }
+
+    fn jj_conflict(&mut self) -> bool {
+        self.jj_change().is_some_and(|change| change.conflict)
+    }
+
+    fn named_dirs(&mut self) -> &[(String, PathBuf)] {
+        &self.named_dirs
+    }
+
+    fn prefetch(&mut self, needs: &Needs) {
+        if needs.status {
+            self.start_status();
+        }
+        if needs.change || needs.branch {
+            self.start_change();
+        }
+        if needs.remote {
+            self.start_remote_info();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_git_origin, GitInfo};
+    use crate::{expand, Domain, Info, Vcs};
+    use git2::{Oid, Repository, Signature};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+    use std::str;
+
+    /// Commit a new file called `name`
+    fn commit(repo: &Repository, name: &str) -> Oid {
+        let root = repo.workdir().unwrap();
+        fs::write(root.join(name), name).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, name, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_git_origin() {
+        let domain = parse_git_origin("git@github.com:path/file.git").unwrap();
+        assert_eq!(domain, "github.com");
+    }
+
+    #[test]
+    fn repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("repo").join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        let mut info = GitInfo::new(&sub);
+        assert!(!info.git_exists());
+        assert!(!info.git_dirty());
+        assert_eq!(info.git_branch(), "");
+
+        let repo = Repository::init(dir.path().join("repo")).unwrap();
+        repo.set_head("refs/heads/trunk").unwrap();
+        commit(&repo, "first");
+        repo.remote("origin", "git@git.example.com:user/repo.git")
+            .unwrap();
+        fs::write(sub.join("file"), "").unwrap();
+        let domains = BTreeMap::from([("git.example.com".to_owned(), Domain::Gitlab)]);
+        let mut info = GitInfo::new(&sub).with_domains(domains);
+        assert!(info.git_exists());
+        assert_eq!(info.current_path(), sub);
+        assert_eq!(
+            info.git_root().unwrap().canonicalize().unwrap(),
+            dir.path().join("repo").canonicalize().unwrap()
+        );
+        let mut result = Vec::new();
+        expand("%r %(y.d.c)%(m.m.)%(s.s.) %x", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "trunk dm 0");
+        assert_eq!(info.computed_status(), Some((true, true, false)));
+        assert_eq!(info.computed_stashes(), Some(0));
+        // NOTE there's no upstream branch yet
+        assert_eq!(info.git_remote_domain(), Domain::Git);
+
+        let mut info = GitInfo::new(&sub);
+        info.set_fast(true);
+        info.set_stashes(3);
+        info.set_remote_info((Domain::Github, 1, 2));
+        result.clear();
+        expand("%(k.s.u) %x %p%q %(o.g.h)", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "u 3 12 h");
+        assert!(info.status_started());
+    }
+
+    #[test]
+    fn mercurial() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let hg_root = root.join("vendored");
+        fs::create_dir_all(hg_root.join(".hg").join("shelved")).unwrap();
+        fs::write(hg_root.join(".hg").join("branch"), "stable\n").unwrap();
+        fs::write(hg_root.join(".hg/shelved/first.patch"), "").unwrap();
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "first");
+
+        let mut info = GitInfo::new(&hg_root);
+        assert!(info.git_exists());
+        assert_eq!(info.git_root(), Some(hg_root.as_path()));
+        let mut result = Vec::new();
+        expand("%r %x %(o.g.h) %R %(R.n.g.h.j)", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "stable 1 g hg h");
+
+        // NOTE the innermost repository wins
+        Repository::init(hg_root.join("nested")).unwrap();
+        let mut info = GitInfo::new(hg_root.join("nested"));
+        assert!(info.repository().is_some());
+        let mut info = GitInfo::new(&root);
+        assert_ne!(info.git_branch(), "stable");
+    }
+
+    #[test]
+    fn jujutsu() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "first");
+        let mut info = GitInfo::new(&root);
+        assert!(!info.jj_exists());
+        assert_eq!(info.vcs(), Vcs::Git);
+        assert_eq!(GitInfo::new(dir.path()).vcs(), Vcs::None);
+
+        // NOTE a colocated repository is read with jujutsu, which is missing here
+        fs::create_dir(root.join(".jj")).unwrap();
+        let mut info = GitInfo::new(&root);
+        assert!(info.jj_exists());
+        assert_eq!(info.vcs(), Vcs::Jj);
+        assert!(info.repository().is_none());
+        assert_eq!(info.git_root(), Some(root.as_path()));
+        let mut result = Vec::new();
+        expand(
+            "%(J.jj.git) [%r] [%J] %(k.%(y.d.c).?)",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "jj [] [] c");
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn subversion_and_fossil() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("wc");
+        fs::create_dir_all(root.join(".svn")).unwrap();
+        let db = rusqlite::Connection::open(root.join(".svn").join("wc.db")).unwrap();
+        db.execute_batch(
+            "CREATE TABLE nodes (local_relpath TEXT, op_depth INTEGER, repos_path TEXT);
+            CREATE TABLE actual_node (properties BLOB, conflict_data BLOB);
+            INSERT INTO nodes VALUES ('', 0, 'trunk');",
+        )
+        .unwrap();
+        let checkout = root.join("tools");
+        fs::create_dir_all(checkout.join("sub")).unwrap();
+        fs::write(checkout.join(".fslckout"), "").unwrap();
+
+        let mut info = GitInfo::new(&root);
+        assert_eq!(info.vcs(), Vcs::Svn);
+        assert_eq!(info.git_root(), Some(root.as_path()));
+        let mut result = Vec::new();
+        expand("%r %R %(G.y.n) %(R.....s.f)", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "trunk svn y s");
+
+        // NOTE the innermost repository wins, even when it can't be read
+        let mut info = GitInfo::new(checkout.join("sub"));
+        assert_eq!(info.vcs(), Vcs::Fossil);
+        assert_eq!(info.git_root(), Some(checkout.as_path()));
+        result.clear();
+        expand("[%r] %R %(R.....s.f)", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[] fossil f");
+        Repository::init(checkout.join("sub")).unwrap();
+        assert_eq!(GitInfo::new(checkout.join("sub")).vcs(), Vcs::Git);
+    }
+
+    /// Assert that every backend reads the repository at `path` the same way
+    fn assert_parity(path: &Path) {
+        use super::{Backend, Repo};
+
+        let domains = BTreeMap::from([("git.example.com".to_owned(), Domain::Gitlab)]);
+        let read = |backend| {
+            let mut repo = Repo::discover(path, backend).unwrap();
+            assert_eq!(repo.backend(), backend);
+            (
+                repo.git_dir().canonicalize().unwrap(),
+                repo.workdir().map(|dir| dir.canonicalize().unwrap()),
+                repo.branch(),
+                repo.head_id(),
+                repo.upstream_id(),
+                repo.status(),
+                repo.remote_info(&domains),
+                repo.stashes(),
+            )
+        };
+        let expected = read(Backend::Cli);
+        let backends = [
+            #[cfg(feature = "git2")]
+            Backend::Git2,
+            #[cfg(feature = "gix")]
+            Backend::Gix,
+            Backend::Cli,
+        ];
+        for backend in backends {
+            assert_eq!(read(backend), expected, "{backend:?} {}", path.display());
+        }
+    }
+
+    #[test]
+    fn parity() {
+        use super::{Backend, Repo};
+
+        let domains = BTreeMap::from([("git.example.com".to_owned(), Domain::Gitlab)]);
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = Repository::init(&root).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        assert_parity(&root.join("sub"));
+
+        fs::write(root.join(".gitignore"), "ignored\n").unwrap();
+        let first = commit(&repo, ".gitignore");
+        fs::write(root.join("ignored"), "").unwrap();
+        assert_parity(&root);
+
+        fs::write(root.join("untracked"), "").unwrap();
+        assert_parity(&root);
+        fs::remove_file(root.join("untracked")).unwrap();
+
+        commit(&repo, "file");
+        fs::write(root.join("file"), "modified").unwrap();
+        assert_parity(&root);
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+        assert_parity(&root);
+
+        let mut repo = repo;
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        repo.stash_save(&sig, "first", None).unwrap();
+        fs::write(root.join("file"), "again").unwrap();
+        repo.stash_save(&sig, "second", None).unwrap();
+        assert_parity(&root);
+
+        let head = commit(&repo, "ahead");
+        repo.remote("origin", "git@git.example.com:user/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/origin/main", first, true, "")
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{branch}.remote"), "origin")
+            .unwrap();
+        config
+            .set_str(&format!("branch.{branch}.merge"), "refs/heads/main")
+            .unwrap();
+        assert_parity(&root);
+        let mut cli = Repo::discover(&root, Backend::Cli).unwrap();
+        assert_eq!(cli.remote_info(&domains), Some((Domain::Gitlab, 2, 0)));
+        assert_eq!(cli.status(), (true, true, false));
+        assert_eq!(cli.stashes(), 2);
+
+        repo.remote_set_url("origin", "https://github.com/user/repo.git")
+            .unwrap();
+        // NOTE the remote is `team/origin`, not `team`
+        repo.remote("team", "https://github.com/team/repo.git")
+            .unwrap();
+        repo.remote("team/origin", "git@git.example.com:team/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/team/origin/main", first, true, "")
+            .unwrap();
+        config
+            .set_str(&format!("branch.{branch}.remote"), "team/origin")
+            .unwrap();
+        assert_parity(&root);
+        let cli = Repo::discover(&root, Backend::Cli).unwrap();
+        assert_eq!(cli.remote_info(&domains), Some((Domain::Gitlab, 2, 0)));
+
+        repo.set_head_detached(head).unwrap();
+        assert_parity(&root);
+
+        let root = dir.path().join("changes");
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "file");
+        commit(&repo, "deleted");
+        fs::remove_file(root.join("deleted")).unwrap();
+        assert_parity(&root);
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("deleted")).unwrap();
+        index.write().unwrap();
+        assert_parity(&root);
+        fs::rename(root.join("file"), root.join("renamed")).unwrap();
+        assert_parity(&root);
+        index.remove_path(Path::new("file")).unwrap();
+        index.add_path(Path::new("renamed")).unwrap();
+        index.write().unwrap();
+        assert_parity(&root);
+
+        let root = dir.path().join("conflicted");
+        let repo = Repository::init(&root).unwrap();
+        let base = repo.find_commit(commit(&repo, "file")).unwrap();
+        fs::write(root.join("conflict"), "theirs").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("conflict")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let theirs = repo
+            .commit(None, &sig, &sig, "theirs", &tree, &[&base])
+            .unwrap();
+        commit(&repo, "conflict");
+        let theirs = repo.find_annotated_commit(theirs).unwrap();
+        repo.merge(&[&theirs], None, None).unwrap();
+        assert!(repo.index().unwrap().has_conflicts());
+        assert_parity(&root);
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn incremental_status() {
+        use super::{Backend, Fsmonitor, Repo, Snapshot};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "first");
+        commit(&repo, "second");
+        // NOTE the hook reports whatever was written to `changed`
+        let changed = dir.path().join("changed");
+        let hook = dir.path().join("hook");
+        fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\nprintf 'token%s\\0' \"$2\"\ntr '\\n' '\\0' < '{}'\n",
+                changed.display()
+            ),
+        )
+        .unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str("core.fsmonitor", &format!("sh {}", hook.display()))
+            .unwrap();
+        let repo = Repo::open(&root, Backend::Git2).unwrap();
+        let report = |paths: &str| fs::write(&changed, paths).unwrap();
+
+        fs::write(root.join("first"), "modified").unwrap();
+        report("");
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, None);
+        assert_eq!(status, (true, true, false));
+        let snapshot = snapshot.unwrap();
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                token: "token".to_owned(),
+                modified: vec!["first".to_owned()],
+            }
+        );
+
+        // NOTE files the monitor doesn't report aren't checked, unless they were modified
+        fs::write(root.join("second"), "modified").unwrap();
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, Some(&snapshot));
+        assert_eq!(status, (true, true, false));
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.token, "tokentoken");
+        assert_eq!(snapshot.modified, ["first"]);
+
+        fs::write(root.join("first"), "first").unwrap();
+        fs::write(root.join("second"), "second").unwrap();
+        fs::write(root.join("untracked"), "").unwrap();
+        report("first\nuntracked\n");
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, Some(&snapshot));
+        assert_eq!(status, (true, true, false));
+        assert_eq!(snapshot.as_ref().unwrap().modified, ["untracked"]);
+
+        fs::remove_file(root.join("untracked")).unwrap();
+        report("untracked\n");
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, snapshot.as_ref());
+        assert_eq!(status, (false, false, false));
+
+        // NOTE new files can be in new directories, which status reports instead of the files
+        fs::create_dir(root.join("newdir")).unwrap();
+        fs::write(root.join("newdir").join("f"), "").unwrap();
+        report("newdir/f\n");
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, snapshot.as_ref());
+        assert_eq!(status, (true, true, false));
+        assert_eq!(snapshot.as_ref().unwrap().modified, ["newdir/f"]);
+        report("");
+        let (status, snapshot) = repo.status_since(&Fsmonitor::Hook, snapshot.as_ref());
+        assert_eq!(status, (true, true, false));
+        let (_, full) = repo.status_since(&Fsmonitor::Hook, None);
+        assert_eq!(full.as_ref().unwrap().modified, ["newdir/"]);
+        let (status, _) = repo.status_since(&Fsmonitor::Hook, full.as_ref());
+        assert_eq!(status, (true, true, false));
+
+        fs::remove_dir_all(root.join("newdir")).unwrap();
+        report("newdir/f\n");
+        let (status, _) = repo.status_since(&Fsmonitor::Hook, snapshot.as_ref());
+        assert_eq!(status, (false, false, false));
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn untrusted_hook() {
+        use super::{Backend, Fsmonitor, Repo};
+        use std::os::unix::fs::chown;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "file");
+        let ran = dir.path().join("ran");
+        repo.config()
+            .unwrap()
+            .set_str("core.fsmonitor", &format!("touch {}; false", ran.display()))
+            .unwrap();
+        let repo = Repo::open(&root, Backend::Git2).unwrap();
+        let _ = repo.status_since(&Fsmonitor::Hook, None);
+        assert!(ran.exists());
+        fs::remove_file(&ran).unwrap();
+
+        // NOTE only root can give the repository away
+        if chown(&root, Some(65534), None).is_err() {
+            return;
+        }
+        fs::write(root.join("file"), "modified").unwrap();
+        let (status, _) = repo.status_since(&Fsmonitor::Hook, None);
+        assert_eq!(status, (true, true, false));
+        assert!(!ran.exists());
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn fallback() {
+        use super::{Backend, Repo};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let repo = Repository::init(&root).unwrap();
+        commit(&repo, "first");
+        assert!(Repo::discover(dir.path(), Backend::Git2).is_none());
+
+        // NOTE git supports this extension, but libgit2 refuses to open the repository
+        let mut config = repo.config().unwrap();
+        config.set_i32("core.repositoryformatversion", 1).unwrap();
+        config.set_bool("extensions.preciousObjects", true).unwrap();
+        fs::write(root.join("untracked"), "").unwrap();
+        let repo = Repo::discover(&root, Backend::Git2).unwrap();
+        assert_eq!(repo.backend(), Backend::Cli);
+        assert_eq!(repo.status(), (true, true, false));
+        let repo = Repo::open(&root, Backend::Git2).unwrap();
+        assert_eq!(repo.backend(), Backend::Cli);
+    }
+}
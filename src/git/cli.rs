@@ -0,0 +1,247 @@
+//! The git command line backend
+//!
+//! Everything but the remote url comes from a single run of `git status`, which is shared between
+//! all clones of a repository, so this works with anything the installed git understands.
+use super::remote_domain;
+use crate::Domain;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+
+/// The parsed output of `git status --porcelain=v2 --branch --show-stash`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Status {
+    /// The commit HEAD points to, unless it's unborn
+    oid: Option<String>,
+    /// The current branch, unless HEAD is detached
+    head: Option<String>,
+    /// Commits ahead and behind the upstream, if it exists
+    ahead_behind: Option<(usize, usize)>,
+    stashes: usize,
+    dirty: bool,
+    modified: bool,
+    staged: bool,
+}
+
+impl Status {
+    fn parse(output: &str) -> Self {
+        let mut status = Status::default();
+        for line in output.lines() {
+            if let Some(header) = line.strip_prefix("# ") {
+                let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+                match key {
+                    "branch.oid" => status.oid = (value != "(initial)").then(|| value.to_owned()),
+                    "branch.head" => {
+                        status.head = (value != "(detached)").then(|| value.to_owned());
+                    }
+                    "branch.ab" => {
+                        status.ahead_behind = value.split_once(' ').and_then(|(ahead, behind)| {
+                            Some((
+                                ahead.strip_prefix('+')?.parse().ok()?,
+                                behind.strip_prefix('-')?.parse().ok()?,
+                            ))
+                        });
+                    }
+                    "stash" => status.stashes = value.parse().unwrap_or_default(),
+                    _ => (),
+                }
+            } else {
+                let mut fields = line.split(' ');
+                match (fields.next(), fields.next().map(str::as_bytes)) {
+                    (Some("1" | "2"), Some(&[index, worktree])) => {
+                        status.dirty = true;
+                        status.staged |= index != b'.';
+                        status.modified |= worktree != b'.';
+                    }
+                    // NOTE like libgit2, conflicts are neither staged nor modified
+                    (Some("u"), _) => status.dirty = true,
+                    (Some("?"), _) => {
+                        status.dirty = true;
+                        status.modified = true;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        status
+    }
+}
+
+/// Run git in `dir`, returning its output if it succeeded
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    // NOTE optional locks would make status refresh the index, which could get in the way of
+    // commands run while the prompt is being computed
+    let output = Command::new("git")
+        .arg("--no-optional-locks")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+/// A repository read with the git command line
+#[derive(Clone)]
+pub(super) struct Repo {
+    git_dir: PathBuf,
+    workdir: Option<PathBuf>,
+    status: Arc<OnceLock<Option<Status>>>,
+}
+
+impl Repo {
+    /// The directory to run commands in
+    fn dir(&self) -> &Path {
+        self.workdir.as_deref().unwrap_or(&self.git_dir)
+    }
+
+    fn status(&self) -> Option<&Status> {
+        self.status
+            .get_or_init(|| {
+                let args = ["status", "--porcelain=v2", "--branch", "--show-stash"];
+                git(self.dir(), &args).map(|output| Status::parse(&output))
+            })
+            .as_ref()
+    }
+
+    pub(super) fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub(super) fn workdir(&self) -> Option<&Path> {
+        self.workdir.as_deref()
+    }
+}
+
+pub(super) fn discover(dir: &Path) -> Option<Repo> {
+    let args = [
+        "rev-parse",
+        "--absolute-git-dir",
+        "--is-bare-repository",
+        "--is-inside-work-tree",
+        "--show-cdup",
+    ];
+    let output = git(dir, &args)?;
+    let mut lines = output.lines();
+    let git_dir = PathBuf::from(lines.next()?);
+    let bare = lines.next()? == "true";
+    let workdir = if lines.next()? == "true" {
+        dir.join(lines.next().unwrap_or_default())
+            .canonicalize()
+            .ok()
+    } else if !bare && git_dir.file_name().is_some_and(|name| name == ".git") {
+        // NOTE this is inside the git directory, which isn't part of the work tree
+        git_dir.parent().map(Path::to_owned)
+    } else {
+        None
+    };
+    Some(Repo {
+        git_dir,
+        workdir,
+        status: Arc::default(),
+    })
+}
+
+pub(super) fn refresh(repo: &mut Repo) {
+    repo.status = Arc::default();
+}
+
+pub(super) fn branch(repo: &Repo) -> Option<String> {
+    let status = repo.status()?;
+    status.oid.as_ref()?;
+    // NOTE this matches the shorthand libgit2 uses for a detached head
+    Some(status.head.clone().unwrap_or_else(|| "HEAD".to_owned()))
+}
+
+pub(super) fn head_id(repo: &Repo) -> Option<String> {
+    repo.status()?.oid.clone()
+}
+
+pub(super) fn upstream_id(repo: &Repo) -> Option<String> {
+    repo.status()?.ahead_behind?;
+    let id = git(
+        repo.dir(),
+        &["rev-parse", "--verify", "--quiet", "@{upstream}"],
+    )?;
+    Some(id.trim_end_matches('\n').to_owned())
+}
+
+pub(super) fn status(repo: &Repo) -> (bool, bool, bool) {
+    repo.status().map_or((false, false, false), |status| {
+        (status.dirty, status.modified, status.staged)
+    })
+}
+
+pub(super) fn remote_info(
+    repo: &Repo,
+    domains: &BTreeMap<String, Domain>,
+) -> Option<(Domain, usize, usize)> {
+    let status = repo.status()?;
+    let (num_ahead, num_behind) = status.ahead_behind?;
+    let branch = status.head.as_ref()?;
+    // NOTE remote names can contain slashes, so this can't be taken from the upstream
+    let url = git(
+        repo.dir(),
+        &["config", "--get", &format!("branch.{branch}.remote")],
+    )
+    .and_then(|remote| {
+        let remote = remote.trim_end_matches('\n');
+        git(
+            repo.dir(),
+            &["config", "--get", &format!("remote.{remote}.url")],
+        )
+    });
+    let domain = url
+        .and_then(|url| remote_domain(url.trim_end_matches('\n'), domains))
+        .unwrap_or(Domain::Git);
+    Some((domain, num_ahead, num_behind))
+}
+
+pub(super) fn stashes(repo: &Repo) -> usize {
+    repo.status().map_or(0, |status| status.stashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Status;
+
+    #[test]
+    fn parse() {
+        let output = "\
+# branch.oid 0123abcd
+# branch.head main
+# branch.upstream origin/main
+# branch.ab +2 -1
+# stash 3
+1 .M N... 100644 100644 100644 0123 0123 modified
+2 R. N... 100644 100644 100644 0123 0123 R100 new\told
+? untracked
+";
+        let status = Status::parse(output);
+        assert_eq!(
+            status,
+            Status {
+                oid: Some("0123abcd".to_owned()),
+                head: Some("main".to_owned()),
+                ahead_behind: Some((2, 1)),
+                stashes: 3,
+                dirty: true,
+                modified: true,
+                staged: true,
+            }
+        );
+
+        let output = "# branch.oid (initial)\n# branch.head (detached)\n";
+        assert_eq!(Status::parse(output), Status::default());
+
+        let status = Status::parse("u UU N... 100644 100644 100644 100644 0 0 0 conflict\n");
+        assert!(status.dirty && !status.modified && !status.staged);
+    }
+}
@@ -0,0 +1,311 @@
+//! Finding out which files changed from a file system monitor
+//!
+//! Instead of walking the whole working directory, status can ask a monitor which files changed
+//! since it was last computed, and only check those along with the files that were already
+//! modified. The monitor is either the hook git is configured to use as `core.fsmonitor`, which
+//! speaks version 2 of git's hook protocol, or watchman queried directly over its socket. Like
+//! git, a hook from a repository's own config is only run if the repository belongs to the user.
+// NOTE only libgit2 updates status incrementally
+#![cfg_attr(not(feature = "git2"), allow(dead_code))]
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// How long to wait for watchman to respond
+const WATCHMAN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to find out which files changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fsmonitor {
+    /// The hook configured as `core.fsmonitor` in the repository, if there is one, and it either
+    /// comes from outside the repository or the repository belongs to the user
+    Hook,
+    /// Watchman listening on a socket, which defaults to `$WATCHMAN_SOCK`, or the one the
+    /// `watchman` command reports
+    Watchman(Option<PathBuf>),
+}
+
+/// The state status was last computed in, so that it can be updated incrementally
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The monitor's token for when status was computed
+    pub token: String,
+    /// Paths relative to the root of the working directory that were modified or untracked
+    pub modified: Vec<String>,
+}
+
+/// The files that changed since a token
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Changes {
+    /// The token for now
+    pub(super) token: String,
+    /// Changed paths relative to the root, or `None` if anything could have changed
+    pub(super) paths: Option<Vec<String>>,
+}
+
+impl Fsmonitor {
+    /// Ask which files in the working directory at `root` changed since `token`
+    ///
+    /// `hook` is the configured `core.fsmonitor`. Without a token this only gets one for now.
+    pub(super) fn changes(
+        &self,
+        root: &Path,
+        hook: Option<&str>,
+        token: Option<&str>,
+    ) -> Option<Changes> {
+        match self {
+            Fsmonitor::Hook => run_hook(hook?, root, token),
+            Fsmonitor::Watchman(socket) => {
+                let socket = match socket {
+                    Some(socket) => socket.clone(),
+                    None => watchman_socket()?,
+                };
+                query_watchman(&socket, root, token)
+            }
+        }
+    }
+}
+
+/// Whether `path` belongs to the user, so that commands configured in it can be trusted
+pub(super) fn owned(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.uid() == rustix::process::getuid().as_raw())
+}
+
+fn run_hook(hook: &str, root: &Path, token: Option<&str>) -> Option<Changes> {
+    // NOTE git runs the hook with the shell, so it can have arguments
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{hook} \"$@\""))
+        .arg(hook)
+        .arg("2")
+        .arg(token.unwrap_or_default())
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let mut entries = output.split('\0');
+    let new_token = entries.next().filter(|token| !token.is_empty())?;
+    let mut paths = Vec::new();
+    for path in entries.filter(|path| !path.is_empty()) {
+        // NOTE a lone slash means that anything could have changed
+        if path == "/" {
+            return Some(Changes {
+                token: new_token.to_owned(),
+                paths: None,
+            });
+        }
+        paths.push(path.to_owned());
+    }
+    Some(Changes {
+        token: new_token.to_owned(),
+        paths: token.map(|_| paths),
+    })
+}
+
+fn watchman_socket() -> Option<PathBuf> {
+    if let Some(socket) = env::var_os("WATCHMAN_SOCK") {
+        return Some(socket.into());
+    }
+    let output = Command::new("watchman")
+        .args(["--output-encoding=json", "--no-pretty", "get-sockname"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let response: Value = serde_json::from_slice(&output.stdout).ok()?;
+    Some(response["sockname"].as_str()?.into())
+}
+
+fn query_watchman(socket: &Path, root: &Path, token: Option<&str>) -> Option<Changes> {
+    let stream = UnixStream::connect(socket).ok()?;
+    stream.set_read_timeout(Some(WATCHMAN_TIMEOUT)).ok()?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = |command: Value| {
+        let mut line = command.to_string();
+        line.push('\n');
+        (&stream).write_all(line.as_bytes()).ok()?;
+        loop {
+            let mut response = String::new();
+            reader.read_line(&mut response).ok()?;
+            let response: Value = serde_json::from_str(&response).ok()?;
+            // NOTE unilateral responses are for subscriptions, not what we asked
+            if response.get("unilateral").is_none() {
+                return response.get("error").is_none().then_some(response);
+            }
+        }
+    };
+
+    let watch = request(json!(["watch-project", root]))?;
+    let watch_root = watch["watch"].as_str()?.to_owned();
+    let Some(token) = token else {
+        let clock = request(json!(["clock", watch_root]))?;
+        return Some(Changes {
+            token: clock["clock"].as_str()?.to_owned(),
+            paths: None,
+        });
+    };
+    let mut query = json!({
+        "since": token,
+        "fields": ["name"],
+        "empty_on_fresh_instance": true,
+    });
+    if let Some(relative) = watch.get("relative_path") {
+        query["relative_root"] = relative.clone();
+    }
+    let response = request(json!(["query", watch_root, query]))?;
+    let paths = if response["is_fresh_instance"].as_bool() == Some(true) {
+        None
+    } else {
+        let files = response["files"].as_array()?;
+        Some(
+            files
+                .iter()
+                .filter_map(|name| Some(name.as_str()?.to_owned()))
+                .collect(),
+        )
+    };
+    Some(Changes {
+        token: response["clock"].as_str()?.to_owned(),
+        paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Changes, Fsmonitor};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    /// Answer watchman requests on `socket` like a watchman watching `root` from its parent
+    fn fake_watchman(socket: &Path, root: &Path) {
+        let listener = UnixListener::bind(socket).unwrap();
+        let watch = root.parent().unwrap().to_str().unwrap().to_owned();
+        let name = root.file_name().unwrap().to_str().unwrap().to_owned();
+        let root = root.to_str().unwrap().to_owned();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut lines = BufReader::new(&stream).lines();
+                while let Some(Ok(line)) = lines.next() {
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    let response = match request[0].as_str().unwrap() {
+                        "watch-project" if request[1] == root.as_str() => {
+                            json!({"watch": watch, "relative_path": name})
+                        }
+                        "clock" if request[1] == watch.as_str() => json!({"clock": "c:1"}),
+                        "query" if request[2]["relative_root"] == name.as_str() => {
+                            match request[2]["since"].as_str().unwrap() {
+                                "c:1" => json!({
+                                    "clock": "c:2",
+                                    "is_fresh_instance": false,
+                                    "files": ["changed", "dir/new"],
+                                }),
+                                _ => json!({
+                                    "clock": "c:3",
+                                    "is_fresh_instance": true,
+                                    "files": [],
+                                }),
+                            }
+                        }
+                        _ => json!({"error": "unexpected request"}),
+                    };
+                    // NOTE real watchman sends these when subscribed, which clients must skip
+                    writeln!(&stream, "{}", json!({"unilateral": true})).unwrap();
+                    writeln!(&stream, "{response}").unwrap();
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn watchman() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let socket = dir.path().join("watchman.sock");
+        fake_watchman(&socket, &root);
+        let monitor = Fsmonitor::Watchman(Some(socket));
+
+        let changes = |token| monitor.changes(&root, None, token);
+        let token = |token: &str| token.to_owned();
+        assert_eq!(
+            changes(None),
+            Some(Changes {
+                token: token("c:1"),
+                paths: None
+            })
+        );
+        assert_eq!(
+            changes(Some("c:1")),
+            Some(Changes {
+                token: token("c:2"),
+                paths: Some(vec![token("changed"), token("dir/new")]),
+            })
+        );
+        assert_eq!(
+            changes(Some("c:0")),
+            Some(Changes {
+                token: token("c:3"),
+                paths: None
+            })
+        );
+        assert_eq!(monitor.changes(dir.path(), None, None), None);
+    }
+
+    #[test]
+    fn hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("hook");
+        fs::write(
+            &hook,
+            "#!/bin/sh\n[ \"$1\" = 2 ] || exit 1\ncase \"$2\" in\n\
+             '') printf 'first\\0' ;;\nfirst) printf 'second\\0a\\0dir/\\0' ;;\n\
+             *) printf 'third\\0a\\0/\\0' ;;\nesac\n",
+        )
+        .unwrap();
+        let hook = format!("sh {}", hook.display());
+
+        let changes = |token| Fsmonitor::Hook.changes(dir.path(), Some(&hook), token);
+        let token = |token: &str| token.to_owned();
+        assert_eq!(
+            changes(None),
+            Some(Changes {
+                token: token("first"),
+                paths: None
+            })
+        );
+        assert_eq!(
+            changes(Some("first")),
+            Some(Changes {
+                token: token("second"),
+                paths: Some(vec![token("a"), token("dir/")]),
+            })
+        );
+        assert_eq!(
+            changes(Some("second")),
+            Some(Changes {
+                token: token("third"),
+                paths: None
+            })
+        );
+        assert_eq!(Fsmonitor::Hook.changes(dir.path(), None, None), None);
+        assert_eq!(
+            Fsmonitor::Hook.changes(dir.path(), Some("false"), None),
+            None
+        );
+    }
+}
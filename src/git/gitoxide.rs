@@ -0,0 +1,111 @@
+//! The gitoxide backend
+use super::remote_domain;
+use crate::Domain;
+use gix::head::Kind;
+use gix::remote::Direction;
+use gix::status::index_worktree::iter::Summary;
+use gix::status::Item;
+use gix::{ObjectId, Repository};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub(super) fn discover(dir: &Path) -> Option<Repository> {
+    gix::discover(dir).ok()
+}
+
+pub(super) fn open(path: &Path) -> Option<Repository> {
+    gix::open(path).ok()
+}
+
+pub(super) fn branch(repo: &Repository) -> Option<String> {
+    match repo.head().ok()?.kind {
+        Kind::Symbolic(reference) => Some(reference.name.shorten().to_string()),
+        // NOTE this matches the shorthand libgit2 uses for a detached head
+        Kind::Detached { .. } => Some("HEAD".to_owned()),
+        Kind::Unborn(_) => None,
+    }
+}
+
+pub(super) fn head_id(repo: &Repository) -> Option<String> {
+    Some(repo.head_id().ok()?.to_string())
+}
+
+/// The commit the upstream of the current branch points to
+fn upstream(repo: &Repository) -> Option<ObjectId> {
+    let head = repo.head_name().ok()??;
+    let upstream = repo
+        .branch_remote_tracking_ref_name(head.as_ref(), Direction::Fetch)?
+        .ok()?;
+    Some(repo.find_reference(upstream.as_ref()).ok()?.id().detach())
+}
+
+pub(super) fn upstream_id(repo: &Repository) -> Option<String> {
+    upstream(repo).map(|id| id.to_string())
+}
+
+pub(super) fn status(repo: &Repository) -> (bool, bool, bool) {
+    let mut dirty = false;
+    let mut modified = false;
+    let mut staged = false;
+
+    let items = repo
+        .status(gix::progress::Discard)
+        .ok()
+        .and_then(|status| status.into_iter(None).ok());
+    for item in items.into_iter().flatten().flatten() {
+        match item {
+            Item::IndexWorktree(item) => match item.summary() {
+                // NOTE like libgit2, conflicts are neither staged nor modified
+                Some(Summary::Conflict) => dirty = true,
+                Some(_) => (dirty, modified) = (true, true),
+                None => (),
+            },
+            Item::TreeIndex(_) => (dirty, staged) = (true, true),
+        }
+        if modified && staged {
+            break;
+        }
+    }
+    (dirty, modified, staged)
+}
+
+/// The number of commits reachable from `tip` but not `hidden`
+fn count_unique(repo: &Repository, tip: ObjectId, hidden: ObjectId) -> Option<usize> {
+    let walk = repo.rev_walk([tip]).with_hidden([hidden]).all().ok()?;
+    Some(walk.flatten().count())
+}
+
+fn get_domain(
+    repo: &Repository,
+    branch: &str,
+    domains: &BTreeMap<String, Domain>,
+) -> Option<Domain> {
+    let remote_name = repo.branch_remote_name(branch, Direction::Fetch)?;
+    let remote = repo.find_remote(remote_name.as_bstr()).ok()?;
+    let url = remote.url(Direction::Fetch)?.to_bstring();
+    remote_domain(&url.to_string(), domains)
+}
+
+pub(super) fn remote_info(
+    repo: &Repository,
+    domains: &BTreeMap<String, Domain>,
+) -> Option<(Domain, usize, usize)> {
+    let head = repo.head_name().ok()??;
+    let local = repo.head_id().ok()?.detach();
+    let upstream = upstream(repo)?;
+    let num_ahead = count_unique(repo, local, upstream).unwrap_or_default();
+    let num_behind = count_unique(repo, upstream, local).unwrap_or_default();
+    let domain = get_domain(repo, &head.shorten().to_string(), domains).unwrap_or(Domain::Git);
+    Some((domain, num_ahead, num_behind))
+}
+
+pub(super) fn stashes(repo: &Repository) -> usize {
+    let Ok(Some(stash)) = repo.try_find_reference("refs/stash") else {
+        return 0;
+    };
+    let mut log = stash.log_iter();
+    log.all()
+        .ok()
+        .flatten()
+        .map_or(0, |entries| entries.flatten().count())
+}
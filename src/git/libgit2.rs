@@ -0,0 +1,219 @@
+//! The libgit2 backend
+use super::fsmonitor::owned;
+use super::{remote_domain, Fsmonitor, Snapshot};
+use crate::Domain;
+use git2::{Branch, BranchType, Config, ConfigLevel, Oid, Repository, StatusOptions, StatusShow};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub(super) fn discover(dir: &Path) -> Option<Repository> {
+    Repository::discover(dir).ok()
+}
+
+pub(super) fn open(path: &Path) -> Option<Repository> {
+    Repository::open(path).ok()
+}
+
+pub(super) fn branch(repo: &Repository) -> Option<String> {
+    let head = repo.head().ok()?;
+    let name = head.shorthand()?;
+    Some(name.to_owned())
+}
+
+pub(super) fn head_id(repo: &Repository) -> Option<String> {
+    Some(repo.head().ok()?.target()?.to_string())
+}
+
+pub(super) fn upstream_id(repo: &Repository) -> Option<String> {
+    let head = repo.head().ok()?;
+    let upstream = repo.branch_upstream_name(head.name()?).ok()?;
+    Some(repo.refname_to_id(upstream.as_str()?).ok()?.to_string())
+}
+
+pub(super) fn status(repo: &Repository) -> (bool, bool, bool) {
+    let mut dirty = false;
+    let mut modified = false;
+    let mut staged = false;
+
+    if let Ok(statuses) = repo.statuses(Some(StatusOptions::new().include_untracked(true))) {
+        for status in statuses.iter() {
+            dirty = true;
+
+            let status = status.status();
+            if !modified && status.is_wt_new()
+                || status.is_wt_modified()
+                || status.is_wt_deleted()
+                || status.is_wt_renamed()
+                || status.is_wt_typechange()
+            {
+                modified = true;
+            }
+            if !staged && status.is_index_new()
+                || status.is_index_modified()
+                || status.is_index_deleted()
+                || status.is_index_renamed()
+                || status.is_index_typechange()
+            {
+                staged = true;
+            }
+
+            if modified && staged {
+                break;
+            }
+        }
+    }
+    (dirty, modified, staged)
+}
+
+/// The hook configured as `core.fsmonitor`, if there is one that can be trusted
+fn fsmonitor_hook(repo: &Repository) -> Option<String> {
+    let config = repo.config().ok()?;
+    let entry = config.get_entry("core.fsmonitor").ok()?;
+    // NOTE cloning a repository shouldn't let it run commands, so like git, its own config is
+    // only trusted if it belongs to the user
+    let local = matches!(
+        entry.level(),
+        ConfigLevel::Local | ConfigLevel::Worktree | ConfigLevel::App
+    );
+    if local && !(owned(repo.path()) && repo.workdir().is_none_or(owned)) {
+        return None;
+    }
+    let hook = entry.value()?.to_owned();
+    // NOTE a boolean turns on git's builtin daemon instead, which only git can talk to
+    (!hook.is_empty() && Config::parse_bool(hook.as_str()).is_err()).then_some(hook)
+}
+
+/// Compute status, only checking `candidates` in the working directory, and also return the
+/// paths there that are modified or untracked
+///
+/// Without candidates the whole working directory is checked.
+fn status_of(
+    repo: &Repository,
+    candidates: Option<&[String]>,
+) -> ((bool, bool, bool), Vec<String>) {
+    let mut dirty = false;
+    let mut modified = false;
+    let mut staged = false;
+    let mut paths = Vec::new();
+
+    let mut options = StatusOptions::new();
+    options.show(StatusShow::Index);
+    if let Ok(statuses) = repo.statuses(Some(&mut options)) {
+        for status in statuses.iter() {
+            dirty = true;
+            let status = status.status();
+            staged |= status.is_index_new()
+                || status.is_index_modified()
+                || status.is_index_deleted()
+                || status.is_index_renamed()
+                || status.is_index_typechange();
+        }
+    }
+
+    if candidates.is_none_or(|candidates| !candidates.is_empty()) {
+        let mut options = StatusOptions::new();
+        options
+            .show(StatusShow::Workdir)
+            .include_untracked(true)
+            .disable_pathspec_match(true);
+        // NOTE a new file in a new directory is reported as the directory, which its path wouldn't
+        // match
+        options.recurse_untracked_dirs(candidates.is_some());
+        for path in candidates.into_iter().flatten() {
+            options.pathspec(path.trim_end_matches('/'));
+        }
+        if let Ok(statuses) = repo.statuses(Some(&mut options)) {
+            for entry in statuses.iter() {
+                dirty = true;
+                let status = entry.status();
+                modified |= status.is_wt_new()
+                    || status.is_wt_modified()
+                    || status.is_wt_deleted()
+                    || status.is_wt_renamed()
+                    || status.is_wt_typechange();
+                if let Some(path) = entry.path() {
+                    paths.push(path.to_owned());
+                }
+            }
+        }
+    }
+    ((dirty, modified, staged), paths)
+}
+
+pub(super) fn status_since(
+    repo: &Repository,
+    fsmonitor: &Fsmonitor,
+    snapshot: Option<&Snapshot>,
+) -> ((bool, bool, bool), Option<Snapshot>) {
+    let Some(root) = repo.workdir() else {
+        return (status(repo), None);
+    };
+    // NOTE the token is taken before the scan, so changes during it show up next time
+    let hook = fsmonitor_hook(repo);
+    let token = snapshot.map(|snapshot| snapshot.token.as_str());
+    let Some(changes) = fsmonitor.changes(root, hook.as_deref(), token) else {
+        return (status(repo), None);
+    };
+    // NOTE files that were modified last time might not be anymore, so they're checked again
+    let candidates = changes.paths.map(|mut paths| {
+        paths.extend(
+            snapshot
+                .into_iter()
+                .flat_map(|snapshot| snapshot.modified.clone()),
+        );
+        paths
+    });
+    let (status, modified) = status_of(repo, candidates.as_deref());
+    let snapshot = Snapshot {
+        token: changes.token,
+        modified,
+    };
+    (status, Some(snapshot))
+}
+
+fn get_ahead_behind(
+    repo: &Repository,
+    local: Oid,
+    upstream_branch: &Branch,
+) -> Option<(usize, usize)> {
+    let upstream = upstream_branch.get().target()?;
+    repo.graph_ahead_behind(local, upstream).ok()
+}
+
+fn get_domain(
+    repo: &Repository,
+    local_branch: &Branch,
+    domains: &BTreeMap<String, Domain>,
+) -> Option<Domain> {
+    // NOTE remote names can contain slashes, so this has to come from `branch.<name>.remote`
+    let remote_name = repo
+        .branch_upstream_remote(local_branch.get().name()?)
+        .ok()?;
+    let remote = repo.find_remote(remote_name.as_str()?).ok()?;
+    remote_domain(remote.url()?, domains)
+}
+
+pub(super) fn remote_info(
+    repo: &Repository,
+    domains: &BTreeMap<String, Domain>,
+) -> Option<(Domain, usize, usize)> {
+    let head = repo.head().ok()?;
+    let branch = head.shorthand()?;
+    let local = head.target()?;
+    let local_branch = repo.find_branch(branch, BranchType::Local).ok()?;
+    let upstream_branch = local_branch.upstream().ok()?;
+    let (num_ahead, num_behind) =
+        get_ahead_behind(repo, local, &upstream_branch).unwrap_or_default();
+    let domain = get_domain(repo, &local_branch, domains).unwrap_or(Domain::Git);
+    Some((domain, num_ahead, num_behind))
+}
+
+pub(super) fn stashes(repo: &mut Repository) -> usize {
+    let mut stashes = 0;
+    repo.stash_foreach(|_, _, _| {
+        stashes += 1;
+        true
+    })
+    .unwrap();
+    stashes
+}
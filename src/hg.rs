@@ -0,0 +1,139 @@
+//! Mercurial information
+//!
+//! The branch, active bookmark, and shelved changes are read from the `.hg` directory, which is
+//! fast. Whether the working directory is dirty needs the dirstate compared with the files, which
+//! is left to `hg status`.
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Run hg in `dir`, returning its output if it succeeded
+fn hg(dir: &Path, args: &[&str]) -> Option<String> {
+    // NOTE plain mode ignores user configuration that changes the output, like aliases and color
+    let output = Command::new("hg")
+        .arg("--cwd")
+        .arg(dir)
+        .args(args)
+        .env("HGPLAIN", "1")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+/// Whether the output of `hg status` has changes, modified files, and staged files
+///
+/// Mercurial has no staging area, so nothing is ever staged, and every change counts as modified.
+fn parse_status(output: &str) -> (bool, bool, bool) {
+    let dirty = output.lines().any(|line| {
+        matches!(
+            line.as_bytes(),
+            [b'M' | b'A' | b'R' | b'!' | b'?', b' ', ..]
+        )
+    });
+    (dirty, dirty, false)
+}
+
+/// A mercurial repository
+#[derive(Debug, Clone)]
+pub struct Repo {
+    root: PathBuf,
+}
+
+impl Repo {
+    /// Find the repository containing `dir`
+    #[must_use]
+    pub fn discover(dir: &Path) -> Option<Self> {
+        let root = dir.ancestors().find(|dir| dir.join(".hg").is_dir())?;
+        Some(Repo {
+            root: root.to_owned(),
+        })
+    }
+
+    /// The root of the working directory
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The active bookmark, or the name of the branch if there isn't one
+    #[must_use]
+    pub fn branch(&self) -> String {
+        let read = |name| fs::read_to_string(self.root.join(".hg").join(name)).ok();
+        let name = read("bookmarks.current").or_else(|| read("branch"));
+        match name.as_deref().map(str::trim) {
+            Some(name) if !name.is_empty() => name.to_owned(),
+            _ => "default".to_owned(),
+        }
+    }
+
+    /// Whether the working directory is dirty, has modified files, and has staged files
+    ///
+    /// This runs `hg status`, and is clean if that fails.
+    #[must_use]
+    pub fn status(&self) -> (bool, bool, bool) {
+        hg(&self.root, &["status"]).map_or((false, false, false), |output| parse_status(&output))
+    }
+
+    /// The number of shelved changes
+    #[must_use]
+    pub fn shelves(&self) -> usize {
+        let Ok(entries) = fs::read_dir(self.root.join(".hg").join("shelved")) else {
+            return 0;
+        };
+        // NOTE every shelve has a patch, along with other files describing it
+        entries
+            .flatten()
+            .filter(|entry| entry.path().extension() == Some(OsStr::new("patch")))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, Repo};
+    use std::fs;
+
+    #[test]
+    fn status() {
+        assert_eq!(parse_status(""), (false, false, false));
+        assert_eq!(parse_status("M modified\n"), (true, true, false));
+        assert_eq!(parse_status("A added\nR removed\n"), (true, true, false));
+        assert_eq!(parse_status("? untracked\n"), (true, true, false));
+        assert_eq!(parse_status("not a status\n"), (false, false, false));
+    }
+
+    #[test]
+    fn repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let sub = root.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        assert!(Repo::discover(&sub).is_none());
+
+        let hg_dir = root.join(".hg");
+        fs::create_dir(&hg_dir).unwrap();
+        let repo = Repo::discover(&sub).unwrap();
+        assert_eq!(repo.root(), root);
+        assert_eq!(repo.branch(), "default");
+        assert_eq!(repo.shelves(), 0);
+
+        fs::write(hg_dir.join("branch"), "stable\n").unwrap();
+        assert_eq!(repo.branch(), "stable");
+        fs::write(hg_dir.join("bookmarks.current"), "feature").unwrap();
+        assert_eq!(repo.branch(), "feature");
+
+        let shelved = hg_dir.join("shelved");
+        fs::create_dir(&shelved).unwrap();
+        for file in ["first.patch", "first.shelve", "second.patch", "second.hg"] {
+            fs::write(shelved.join(file), "").unwrap();
+        }
+        assert_eq!(repo.shelves(), 2);
+    }
+}
@@ -0,0 +1,136 @@
+//! Scripts to integrate with shells
+//!
+//! The generated scripts start by setting the prompts to expand, and then add a `precmd` hook that
+//! expands them, falling back to the prompts from before they were loaded if `shibuichi` is
+//! missing or fails.
+use std::fmt::Write;
+
+/// Options for the generated script
+#[derive(clap::Args, Debug, Default)]
+pub struct Options {
+    /// The left prompt to expand
+    ///
+    /// Defaults to `$SHIBUICHI_PROMPT` if set, otherwise `$PROMPT` when the script is loaded.
+    #[clap(long, value_name = "PROMPT")]
+    left: Option<String>,
+
+    /// The right prompt to expand
+    ///
+    /// Defaults to `$SHIBUICHI_RPROMPT` if set, otherwise `$RPROMPT` when the script is loaded.
+    #[clap(long, value_name = "PROMPT")]
+    right: Option<String>,
+
+    /// Additional prompts to expand into `$psvar`
+    ///
+    /// These can be referenced in the main prompts with `%1v`, `%2v`, etc. Defaults to the
+    /// `$SHIBUICHI_PSVAR` array.
+    #[clap(long, value_name = "PROMPT")]
+    psvar: Vec<String>,
+
+    /// Expand the prompts before drawing them instead of updating them in the background
+    #[clap(long)]
+    sync: bool,
+}
+
+/// Quote a string for zsh
+fn quote(raw: &str) -> String {
+    format!("'{}'", raw.replace('\'', r"'\''"))
+}
+
+/// Generate the zsh integration
+pub fn zsh(opts: &Options) -> String {
+    let mut script = String::new();
+    script.push_str(concat!(
+        "# shibuichi zsh integration\n",
+        "#\n",
+        "# Load with `eval \"$(shibuichi init zsh)\"` in your .zshrc. The prompts to expand are\n",
+        "# SHIBUICHI_PROMPT and SHIBUICHI_RPROMPT, and SHIBUICHI_PSVAR is expanded into psvar.\n",
+        "\n",
+    ));
+    match &opts.left {
+        Some(left) => writeln!(script, "typeset -g SHIBUICHI_PROMPT={}", quote(left)),
+        None => writeln!(
+            script,
+            "typeset -g SHIBUICHI_PROMPT=${{SHIBUICHI_PROMPT-$PROMPT}}"
+        ),
+    }
+    .unwrap();
+    match &opts.right {
+        Some(right) => writeln!(script, "typeset -g SHIBUICHI_RPROMPT={}", quote(right)),
+        None => writeln!(
+            script,
+            "typeset -g SHIBUICHI_RPROMPT=${{SHIBUICHI_RPROMPT-$RPROMPT}}"
+        ),
+    }
+    .unwrap();
+    if opts.psvar.is_empty() {
+        writeln!(script, "typeset -ga SHIBUICHI_PSVAR").unwrap();
+    } else {
+        let psvar: Vec<_> = opts.psvar.iter().map(|prompt| quote(prompt)).collect();
+        writeln!(script, "typeset -ga SHIBUICHI_PSVAR=({})", psvar.join(" ")).unwrap();
+    }
+    script.push('\n');
+    script.push_str(if opts.sync {
+        include_str!("init/sync.zsh")
+    } else {
+        include_str!("init/async.zsh")
+    });
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, zsh, Options};
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("%r %#"), "'%r %#'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn options() {
+        let script = zsh(&Options::default());
+        assert!(script.contains("SHIBUICHI_PROMPT=${SHIBUICHI_PROMPT-$PROMPT}\n"));
+        assert!(script.contains("typeset -ga SHIBUICHI_PSVAR\n"));
+        assert!(script.contains("zle -F"));
+
+        let script = zsh(&Options {
+            left: Some("%r %# ".to_owned()),
+            right: Some("%x".to_owned()),
+            psvar: vec!["%p".to_owned(), "$'s".to_owned()],
+            sync: true,
+        });
+        assert!(script.contains("SHIBUICHI_PROMPT='%r %# '\n"));
+        assert!(script.contains("SHIBUICHI_RPROMPT='%x'\n"));
+        assert!(script.contains(r"SHIBUICHI_PSVAR=('%p' '$'\''s')"));
+        assert!(!script.contains("zle -F"));
+    }
+
+    #[test]
+    #[ignore = "needs zsh, run with --include-ignored"]
+    fn zsh_syntax() {
+        for sync in [false, true] {
+            let script = zsh(&Options {
+                left: Some("%r '%#' ".to_owned()),
+                psvar: vec!["%p".to_owned()],
+                sync,
+                ..Options::default()
+            });
+            let mut child = Command::new("zsh")
+                .arg("-n")
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(script.as_bytes())
+                .unwrap();
+            assert!(child.wait().unwrap().success(), "{script}");
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Jujutsu information
+//!
+//! Everything comes from a single run of `jj log` on the working copy change, which is shared
+//! between all clones of a repository. This doesn't snapshot the working copy, since that would
+//! add an operation every time the prompt is drawn, so whether the change is empty is as of the
+//! last jj command.
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+
+/// The template for the working copy change, with one field per line
+const TEMPLATE: &str = r#"change_id.shortest(8) ++ "\n"
+    ++ local_bookmarks.map(|bookmark| bookmark.name()).join(" ") ++ "\n"
+    ++ if(empty, "1", "0") ++ "\n"
+    ++ if(conflict, "1", "0") ++ "\n""#;
+
+/// The working copy change, `@`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The shortest unique prefix of the change id, but at least 8 characters
+    pub change_id: String,
+    /// The local bookmarks pointing at the change, separated by spaces
+    pub bookmarks: String,
+    /// Whether the change has no modifications
+    pub empty: bool,
+    /// Whether the change has unresolved conflicts
+    pub conflict: bool,
+}
+
+impl Change {
+    fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines();
+        Some(Change {
+            change_id: lines.next()?.to_owned(),
+            bookmarks: lines.next()?.to_owned(),
+            empty: lines.next()? == "1",
+            conflict: lines.next()? == "1",
+        })
+    }
+}
+
+/// A jujutsu repository, which can be colocated with git
+#[derive(Debug, Clone)]
+pub struct Repo {
+    root: PathBuf,
+    change: Arc<OnceLock<Option<Change>>>,
+}
+
+impl Repo {
+    /// Find the repository containing `dir`
+    #[must_use]
+    pub fn discover(dir: &Path) -> Option<Self> {
+        let root = dir.ancestors().find(|dir| dir.join(".jj").is_dir())?;
+        Some(Repo {
+            root: root.to_owned(),
+            change: Arc::default(),
+        })
+    }
+
+    /// The root of the working copy
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The working copy change, if `jj` could read it
+    #[must_use]
+    pub fn change(&self) -> Option<&Change> {
+        self.change
+            .get_or_init(|| {
+                let output = Command::new("jj")
+                    .args(["--no-pager", "--color", "never", "--repository"])
+                    .arg(&self.root)
+                    .args([
+                        "--ignore-working-copy",
+                        "log",
+                        "--no-graph",
+                        "--revisions",
+                        "@",
+                    ])
+                    .arg("--template")
+                    .arg(TEMPLATE)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::null())
+                    .output()
+                    .ok()?;
+                if output.status.success() {
+                    Change::parse(&String::from_utf8(output.stdout).ok()?)
+                } else {
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// Whether the working copy is dirty, has modified files, and has staged files
+    ///
+    /// Jujutsu has no staging area, so the working copy is dirty and modified if its change isn't
+    /// empty, as of the last snapshot, and nothing is ever staged.
+    #[must_use]
+    pub fn status(&self) -> (bool, bool, bool) {
+        let modified = self.change().is_some_and(|change| !change.empty);
+        (modified, modified, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Repo};
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Change::parse("kntqzsqt\nmain feature\n0\n1\n"),
+            Some(Change {
+                change_id: "kntqzsqt".to_owned(),
+                bookmarks: "main feature".to_owned(),
+                empty: false,
+                conflict: true,
+            })
+        );
+        assert_eq!(
+            Change::parse("kntqzsqt\n\n1\n0\n"),
+            Some(Change {
+                change_id: "kntqzsqt".to_owned(),
+                bookmarks: String::new(),
+                empty: true,
+                conflict: false,
+            })
+        );
+        assert_eq!(Change::parse("kntqzsqt\n"), None);
+    }
+
+    #[test]
+    fn discover() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        assert!(Repo::discover(&root.join("sub")).is_none());
+        fs::create_dir(root.join(".jj")).unwrap();
+        let repo = Repo::discover(&root.join("sub")).unwrap();
+        assert_eq!(repo.root(), root);
+    }
+    #[test]
+    #[ignore = "needs jj, run with --include-ignored"]
+    fn read_only() {
+        let operations = |root: &Path| {
+            let output = Command::new("jj")
+                .arg("--repository")
+                .arg(root)
+                .args(["--ignore-working-copy", "op", "log", "--no-graph"])
+                .args(["--template", "id ++ \"\\n\""])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().lines().count()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let status = Command::new("jj")
+            .args(["git", "init"])
+            .arg(&root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        fs::write(root.join("file"), "contents").unwrap();
+        // NOTE drawing the prompt shouldn't snapshot the new file
+        let before = operations(&root);
+        let repo = Repo::discover(&root).unwrap();
+        assert!(repo.change().is_some());
+        assert_eq!(operations(&root), before);
+    }
+}
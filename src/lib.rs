@@ -2,52 +2,84 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs, clippy::pedantic)]
 
+pub mod ast;
+pub mod extensions;
+#[cfg(feature = "sqlite")]
+pub mod fossil;
+pub mod git;
+pub mod hg;
+pub mod jj;
+#[cfg(feature = "sqlite")]
+pub mod svn;
 pub mod util;
 
+use extensions::Extensions;
+pub use git::GitInfo;
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, is_not, tag, take_until},
+    bytes::complete::{escaped, is_not, tag, take_until, take_while1},
     character::complete::{anychar, char, i64, none_of, one_of},
-    combinator::{map, opt},
-    multi::{many0, separated_list0},
+    combinator::{cut, map, map_opt, opt, recognize, verify},
+    error::{context, ContextError, ErrorKind, ParseError as NomParseError},
+    multi::{many0, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
-    Finish, IResult,
+    Finish, IResult, Parser,
 };
-use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
 use std::io;
 use std::io::Write;
+use std::ops::{BitOr, BitOrAssign};
 use std::path;
-use std::path::Path;
-use util::ContainsChar;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use util::{ContainsChar, PathPattern, PrefixMatch};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct Escape(char);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct NumericEscape(Option<i64>, char);
 
-#[derive(Debug, PartialEq)]
-struct DateFormat<'a>(&'a str);
+#[derive(Debug, Clone, PartialEq)]
+struct DateFormat<'a>(Cow<'a, str>);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct NamedColor<'a> {
     num: Option<i64>,
     code: char,
-    name: &'a str,
+    name: Cow<'a, str>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
+struct PathOptions<'a> {
+    longest: bool,
+    expand: bool,
+    glob: bool,
+    sep: Option<Cow<'a, str>>,
+    root: Option<Cow<'a, str>>,
+    last: Option<Cow<'a, str>>,
+    repo: Option<Cow<'a, str>>,
+    alias: Option<Cow<'a, str>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct PathPrefix<'a> {
     num: Option<i64>,
     code: char,
+    options: PathOptions<'a>,
     delim: char,
-    prefix_subs: Vec<(&'a str, &'a str)>,
+    prefix_subs: Vec<(Cow<'a, str>, Cow<'a, str>)>,
 }
 
-#[derive(Debug, PartialEq)]
-struct EscapeLiteral<'a>(&'a str);
+#[derive(Debug, Clone, PartialEq)]
+struct EscapeLiteral<'a>(Cow<'a, str>);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct Conditional<'a> {
     num: Option<i64>,
     code: char,
@@ -56,7 +88,7 @@ struct Conditional<'a> {
     false_branch: Vec<Element<'a>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct AdvancedConditional<'a> {
     code: char,
     delim: char,
@@ -64,14 +96,21 @@ struct AdvancedConditional<'a> {
     conditions: Vec<Vec<Element<'a>>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct Truncation<'a> {
     num: Option<i64>,
     code: char,
-    replacement: &'a str,
+    replacement: Cow<'a, str>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Macro<'a> {
+    name: Cow<'a, str>,
+    // NOTE this is only filled in once macros are resolved
+    body: Option<Vec<Element<'a>>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum Element<'a> {
     Character(char),
     Escape(Escape),
@@ -83,98 +122,317 @@ enum Element<'a> {
     AdvancedConditional(AdvancedConditional<'a>),
     Truncation(Truncation<'a>),
     PathPrefix(PathPrefix<'a>),
+    Macro(Macro<'a>),
+}
+
+/// Copy borrowed text, so it no longer borrows from the prompt
+fn owned(text: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(text.into_owned())
+}
+
+impl Element<'_> {
+    /// Copy any text borrowed from the prompt, see [`Prompt`]
+    fn into_owned(self) -> Element<'static> {
+        let owned_all =
+            |elems: Vec<Element<'_>>| elems.into_iter().map(Element::into_owned).collect();
+        match self {
+            Element::Character(chr) => Element::Character(chr),
+            Element::Escape(esc) => Element::Escape(esc),
+            Element::NumericEscape(esc) => Element::NumericEscape(esc),
+            Element::DateFormat(DateFormat(format)) => {
+                Element::DateFormat(DateFormat(owned(format)))
+            }
+            Element::NamedColor(NamedColor { num, code, name }) => {
+                Element::NamedColor(NamedColor {
+                    num,
+                    code,
+                    name: owned(name),
+                })
+            }
+            Element::EscapeLiteral(EscapeLiteral(literal)) => {
+                Element::EscapeLiteral(EscapeLiteral(owned(literal)))
+            }
+            Element::Conditional(cond) => Element::Conditional(Conditional {
+                num: cond.num,
+                code: cond.code,
+                delim: cond.delim,
+                true_branch: owned_all(cond.true_branch),
+                false_branch: owned_all(cond.false_branch),
+            }),
+            Element::AdvancedConditional(cond) => {
+                Element::AdvancedConditional(AdvancedConditional {
+                    code: cond.code,
+                    delim: cond.delim,
+                    conditions: cond.conditions.into_iter().map(owned_all).collect(),
+                })
+            }
+            Element::Truncation(trunc) => Element::Truncation(Truncation {
+                num: trunc.num,
+                code: trunc.code,
+                replacement: owned(trunc.replacement),
+            }),
+            Element::PathPrefix(path) => Element::PathPrefix(PathPrefix {
+                num: path.num,
+                code: path.code,
+                options: PathOptions {
+                    longest: path.options.longest,
+                    expand: path.options.expand,
+                    glob: path.options.glob,
+                    sep: path.options.sep.map(owned),
+                    root: path.options.root.map(owned),
+                    last: path.options.last.map(owned),
+                    repo: path.options.repo.map(owned),
+                    alias: path.options.alias.map(owned),
+                },
+                delim: path.delim,
+                prefix_subs: path
+                    .prefix_subs
+                    .into_iter()
+                    .map(|(alias, prefix)| (owned(alias), owned(prefix)))
+                    .collect(),
+            }),
+            Element::Macro(mac) => Element::Macro(Macro {
+                name: owned(mac.name),
+                body: mac.body.map(owned_all),
+            }),
+        }
+    }
+}
+
+/// The errors our parsers can produce
+///
+/// Lenient parsing uses nom's default error, while [`check`] uses [`StrictError`] to find out what
+/// was expected.
+trait PromptError<'a>: NomParseError<&'a str> + ContextError<&'a str> {}
+
+impl<'a, E: NomParseError<&'a str> + ContextError<&'a str>> PromptError<'a> for E {}
+
+/// Require `parser` to succeed when parsing strictly
+///
+/// This is used after the start of a construct has been recognized, so that malformed constructs
+/// are reported instead of falling back to plain characters.
+fn commit<'a, const STRICT: bool, O, E: PromptError<'a>>(
+    mut parser: impl Parser<&'a str, O, E>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O, E> {
+    move |input| {
+        if STRICT {
+            cut(|inp| parser.parse(inp))(input)
+        } else {
+            parser.parse(input)
+        }
+    }
 }
 
-fn escape(input: &str) -> IResult<&str, Escape> {
-    let chars = one_of("%)lMny#?eh!iIjLTt@*wWBbEUuSsDrpqx");
+/// Escapes without arguments, including ours
+const ESCAPE_CODES: &str = "%)lMny#?eh!iIjLTt@*wWBbEUuSsDrpqxJR";
+/// Escapes with an optional numeric argument
+const NUMERIC_CODES: &str = "m_^d/~Nc.CvFfKkG";
+/// Conditional codes, including ours
+const CONDITIONAL_CODES: &str = "!#?_C/c.~DdegjLlSTtvVwGymsopqxkJEXR";
+/// Conditional codes that can also be used with more than two branches
+const ADVANCED_CONDITIONAL_CODES: &str = "opqxR";
+
+fn escape<'a, E: PromptError<'a>>(ext: &Extensions, input: &'a str) -> IResult<&'a str, Escape, E> {
+    let chars = context(
+        "an escape",
+        alt((
+            one_of(ESCAPE_CODES),
+            verify(anychar, |code| ext.escapes.contains_key(code)),
+        )),
+    );
     map(preceded(char('%'), chars), Escape)(input)
 }
 
-fn numeric_escape(input: &str) -> IResult<&str, NumericEscape> {
-    let pat = preceded(char('%'), pair(opt(i64), one_of("m_^d/~Nc.CvFfKkG")));
+fn numeric_escape<'a, E: PromptError<'a>>(input: &'a str) -> IResult<&'a str, NumericEscape, E> {
+    let pat = preceded(char('%'), pair(opt(i64), one_of(NUMERIC_CODES)));
     map(pat, |(num, chr)| NumericEscape(num, chr))(input)
 }
 
-fn date_format(input: &str) -> IResult<&str, DateFormat> {
-    map(delimited(tag("%D{"), is_not("}"), char('}')), DateFormat)(input)
+fn date_format<'a, const STRICT: bool, E: PromptError<'a>>(
+    input: &'a str,
+) -> IResult<&'a str, DateFormat<'a>, E> {
+    let format = terminated(alt((is_not("}"), tag(""))), char('}'));
+    map(
+        preceded(tag("%D{"), commit::<STRICT, _, _>(format)),
+        |format: &str| DateFormat(format.into()),
+    )(input)
 }
 
-fn named_color(input: &str) -> IResult<&str, NamedColor> {
-    let (input, (_, num, code, _, name, _)) = tuple((
-        char('%'),
-        opt(i64),
-        one_of("FK"),
-        char('{'),
-        is_not("}"),
-        char('}'),
-    ))(input)?;
-    Ok((input, NamedColor { num, code, name }))
+fn named_color<'a, const STRICT: bool, E: PromptError<'a>>(
+    input: &'a str,
+) -> IResult<&'a str, NamedColor<'a>, E> {
+    let (input, (_, num, code, _)) = tuple((char('%'), opt(i64), one_of("FK"), char('{')))(input)?;
+    let (input, name) =
+        commit::<STRICT, _, _>(terminated(alt((is_not("}"), tag(""))), char('}')))(input)?;
+    Ok((
+        input,
+        NamedColor {
+            num,
+            code,
+            name: name.into(),
+        },
+    ))
 }
 
-fn path_prefix(input: &str) -> IResult<&str, PathPrefix> {
-    let (input, (_, num, code, _, delim)) =
-        tuple((char('%'), opt(i64), one_of("d/"), char('{'), anychar))(input)?;
-    let delim_str = format!("{delim}}}");
-    let (input, prefix_subs) = terminated(
+fn path_options<'a, E: PromptError<'a>>(input: &'a str) -> IResult<&'a str, PathOptions<'a>, E> {
+    let option = pair(
+        take_while1(|chr: char| chr.is_ascii_alphanumeric() || chr == '-'),
+        opt(preceded(
+            char('='),
+            alt((escaped(none_of("\\,]"), '\\', anychar), tag(""))),
+        )),
+    );
+    let options = delimited(char('['), separated_list1(char(','), option), char(']'));
+    map_opt(options, |options| {
+        let mut parsed = PathOptions::default();
+        for option in options {
+            match option {
+                ("longest", None) => parsed.longest = true,
+                ("expand", None) => parsed.expand = true,
+                ("glob", None) => parsed.glob = true,
+                ("sep", Some(sep)) => parsed.sep = Some(sep.into()),
+                ("root", Some(style)) => parsed.root = Some(style.into()),
+                ("last", Some(style)) => parsed.last = Some(style.into()),
+                ("repo", Some(style)) => parsed.repo = Some(style.into()),
+                ("alias", Some(style)) => parsed.alias = Some(style.into()),
+                _ => return None,
+            }
+        }
+        Some(parsed)
+    })(input)
+}
+
+fn path_prefix<'a, const STRICT: bool, E: PromptError<'a>>(
+    input: &'a str,
+) -> IResult<&'a str, PathPrefix<'a>, E> {
+    let (input, (_, num, code, _)) = tuple((char('%'), opt(i64), one_of("d/"), char('{')))(input)?;
+    let (input, (options, delim)) =
+        commit::<STRICT, _, _>(pair(opt(path_options), context("a delimiter", anychar)))(input)?;
+    let delim_str = format!("\\{delim}}}");
+    // NOTE `${NAME}` is allowed to contain the closing brace unescaped
+    let normal = || {
+        alt((
+            recognize(delimited(tag("${"), is_not(&*delim_str), char('}'))),
+            recognize(none_of(&*delim_str)),
+        ))
+    };
+    let (input, prefix_subs) = commit::<STRICT, _, _>(terminated(
         separated_list0(
             char(delim),
             separated_pair(
-                alt((escaped(none_of(&*delim_str), '\\', anychar), tag(""))),
+                alt((escaped(normal(), '\\', anychar), tag(""))),
                 char(delim),
-                escaped(none_of(&*delim_str), '\\', anychar),
+                escaped(normal(), '\\', anychar),
             ),
         ),
         char('}'),
-    )(input)?;
+    ))(input)?;
     Ok((
         input,
         PathPrefix {
             num,
             code,
+            options: options.unwrap_or_default(),
             delim,
-            prefix_subs,
+            prefix_subs: prefix_subs
+                .into_iter()
+                .map(|(alias, prefix)| (alias.into(), prefix.into()))
+                .collect(),
         },
     ))
 }
 
-fn escape_literal(input: &str) -> IResult<&str, EscapeLiteral> {
+fn escape_literal<'a, const STRICT: bool, E: PromptError<'a>>(
+    input: &'a str,
+) -> IResult<&'a str, EscapeLiteral<'a>, E> {
     // NOTE this will fail if we see `%{%%}`, but maybe that's okay?
+    let literal = terminated(context("`%}`", take_until("%}")), tag("%}"));
     map(
-        delimited(tag("%{"), take_until("%}"), tag("%}")),
-        EscapeLiteral,
+        preceded(tag("%{"), commit::<STRICT, _, _>(literal)),
+        |literal: &str| EscapeLiteral(literal.into()),
     )(input)
 }
 
-fn elements_until<'a>(
-    stop: impl ContainsChar + 'a,
-) -> impl Fn(&str) -> IResult<&str, (Vec<Element>, char)> + 'a {
-    move |mut inp| {
-        let mut elems = Vec::new();
-        loop {
-            let (nxt, elem) = element(inp)?;
-            inp = nxt;
-            match elem {
-                Element::Character(chr) if stop.contains(chr) => {
-                    return Ok((inp, (elems, chr)));
-                }
-                _ => {
-                    elems.push(elem);
-                }
+/// Parse a reference to a macro
+///
+/// `%{@name}` also starts a zsh literal, so if `macros` are known and `name` isn't one of them,
+/// this only matches if there's no `%}` to end the literal, so that it's reported as undefined.
+fn macro_ref<'a, E: PromptError<'a>>(
+    macros: Option<&BTreeMap<String, String>>,
+    input: &'a str,
+) -> IResult<&'a str, Macro<'a>, E> {
+    let (rest, name) = delimited(
+        tag("%{@"),
+        take_while1(|chr: char| chr.is_alphanumeric() || chr == '_' || chr == '-'),
+        char('}'),
+    )(input)?;
+    if macros.is_some_and(|macros| !macros.contains_key(name)) && rest.contains("%}") {
+        return Err(nom::Err::Error(E::from_error_kind(
+            input,
+            ErrorKind::Verify,
+        )));
+    }
+    Ok((
+        rest,
+        Macro {
+            name: name.into(),
+            body: None,
+        },
+    ))
+}
+
+/// Parse elements until one of the `stop` characters, which is returned
+///
+/// If the input ends first, the error says `expected` was expected.
+fn elements_until<'a, const STRICT: bool, E: PromptError<'a>>(
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+    stop: impl ContainsChar,
+    expected: char,
+    mut inp: &'a str,
+) -> IResult<&'a str, (Vec<Element<'a>>, char), E> {
+    let mut elems = Vec::new();
+    loop {
+        if inp.is_empty() {
+            return Err(nom::Err::Error(E::from_char(inp, expected)));
+        }
+        let (nxt, elem) = element::<STRICT, E>(ext, macros, inp)?;
+        inp = nxt;
+        match elem {
+            Element::Character(chr) if stop.contains(chr) => {
+                return Ok((inp, (elems, chr)));
+            }
+            _ => {
+                elems.push(elem);
             }
         }
     }
 }
 
-fn conditional(input: &str) -> IResult<&str, Conditional> {
-    let (input, (_, num, _, code, delim)) = tuple((
+fn conditional<'a, const STRICT: bool, E: PromptError<'a>>(
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+    input: &'a str,
+) -> IResult<&'a str, Conditional<'a>, E> {
+    let (input, (_, num, _, code)) = tuple((
         char('%'),
         opt(i64),
         char('('),
-        one_of("!#?_C/c.~DdegjLlSTtvVwGymsopqx"),
-        anychar,
+        context(
+            "a conditional code",
+            alt((
+                one_of(CONDITIONAL_CODES),
+                verify(anychar, |code| ext.conditionals.contains_key(code)),
+            )),
+        ),
     ))(input)?;
-    let (input, (true_branch, _)) = elements_until(delim)(input)?;
-    let (input, (false_branch, _)) = elements_until(')')(input)?;
+    let (input, (delim, true_branch, false_branch)) = commit::<STRICT, _, _>(|input| {
+        let (input, delim) = context("a delimiter", anychar)(input)?;
+        let (input, (true_branch, _)) =
+            elements_until::<STRICT, E>(ext, macros, delim, delim, input)?;
+        let (input, (false_branch, _)) = elements_until::<STRICT, E>(ext, macros, ')', ')', input)?;
+        Ok((input, (delim, true_branch, false_branch)))
+    })(input)?;
     Ok((
         input,
         Conditional {
@@ -187,17 +445,29 @@ fn conditional(input: &str) -> IResult<&str, Conditional> {
     ))
 }
 
-fn advanced_conditional(input: &str) -> IResult<&str, AdvancedConditional> {
-    let (mut input, (code, delim)) = preceded(tag("%("), pair(one_of("opqx"), anychar))(input)?;
-    let delims = [delim, ')'];
-    let mut conditions = Vec::new();
-    let mut found = delim;
-    while found != ')' {
-        let (inp, (elems, fnd)) = elements_until(&delims)(input)?;
-        conditions.push(elems);
-        input = inp;
-        found = fnd;
-    }
+fn advanced_conditional<'a, const STRICT: bool, E: PromptError<'a>>(
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+    input: &'a str,
+) -> IResult<&'a str, AdvancedConditional<'a>, E> {
+    let codes = alt((
+        one_of(ADVANCED_CONDITIONAL_CODES),
+        verify(anychar, |code| ext.advanced_conditionals.contains_key(code)),
+    ));
+    let (input, code) = preceded(tag("%("), codes)(input)?;
+    let (input, (delim, conditions)) = commit::<STRICT, _, _>(|input| {
+        let (mut input, delim) = context("a delimiter", anychar)(input)?;
+        let delims = [delim, ')'];
+        let mut conditions = Vec::new();
+        let mut found = delim;
+        while found != ')' {
+            let (inp, (elems, fnd)) = elements_until::<STRICT, E>(ext, macros, delims, ')', input)?;
+            conditions.push(elems);
+            input = inp;
+            found = fnd;
+        }
+        Ok((input, (delim, conditions)))
+    })(input)?;
     Ok((
         input,
         AdvancedConditional {
@@ -208,38 +478,236 @@ fn advanced_conditional(input: &str) -> IResult<&str, AdvancedConditional> {
     ))
 }
 
-fn truncation(input: &str) -> IResult<&str, Truncation> {
+fn truncation<'a, const STRICT: bool, E: PromptError<'a>>(
+    input: &'a str,
+) -> IResult<&'a str, Truncation<'a>, E> {
     let (input, (num, code)) = preceded(char('%'), pair(opt(i64), one_of("<>")))(input)?;
     let blocked = format!("\\{code}");
-    let (input, replacement) = terminated(
+    let (input, replacement) = commit::<STRICT, _, _>(terminated(
         alt((escaped(none_of(&*blocked), '\\', anychar), tag(""))),
         char(code),
-    )(input)?;
+    ))(input)?;
     Ok((
         input,
         Truncation {
             num,
             code,
-            replacement,
+            replacement: replacement.into(),
         },
     ))
 }
 
-fn element(input: &str) -> IResult<&str, Element> {
+fn element<'a, const STRICT: bool, E: PromptError<'a>>(
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+    input: &'a str,
+) -> IResult<&'a str, Element<'a>, E> {
     alt((
-        map(truncation, Element::Truncation),
-        map(advanced_conditional, Element::AdvancedConditional),
-        map(conditional, Element::Conditional),
-        map(date_format, Element::DateFormat),
-        map(named_color, Element::NamedColor),
-        map(path_prefix, Element::PathPrefix),
-        map(escape_literal, Element::EscapeLiteral),
+        map(truncation::<STRICT, E>, Element::Truncation),
+        map(
+            |inp| advanced_conditional::<STRICT, E>(ext, macros, inp),
+            Element::AdvancedConditional,
+        ),
+        map(
+            |inp| conditional::<STRICT, E>(ext, macros, inp),
+            Element::Conditional,
+        ),
+        map(date_format::<STRICT, E>, Element::DateFormat),
+        map(named_color::<STRICT, E>, Element::NamedColor),
+        map(path_prefix::<STRICT, E>, Element::PathPrefix),
+        map(|inp| macro_ref(macros, inp), Element::Macro),
+        map(escape_literal::<STRICT, E>, Element::EscapeLiteral),
         map(numeric_escape, Element::NumericEscape),
-        map(escape, Element::Escape),
-        map(anychar, Element::Character),
+        map(|inp| escape(ext, inp), Element::Escape),
+        // NOTE when parsing strictly every `%` has to start a valid expansion
+        map(
+            |inp| {
+                if STRICT {
+                    none_of("%")(inp)
+                } else {
+                    anychar(inp)
+                }
+            },
+            Element::Character,
+        ),
     ))(input)
 }
 
+/// What the strict parser expected to find
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// A specific character
+    Char(char),
+    /// A description of the expected syntax
+    Syntax(&'static str),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Char(chr) => write!(f, "`{chr}`"),
+            Expected::Syntax(desc) => write!(f, "{desc}"),
+        }
+    }
+}
+
+/// An error from the strict parser
+///
+/// Of all the alternatives that were tried, this keeps the one that got furthest into the input,
+/// since that's most likely what was intended.
+#[derive(Debug)]
+struct StrictError<'a> {
+    input: &'a str,
+    expected: Option<Expected>,
+}
+
+impl<'a> NomParseError<&'a str> for StrictError<'a> {
+    fn from_error_kind(input: &'a str, _: ErrorKind) -> Self {
+        StrictError {
+            input,
+            expected: None,
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: &'a str, chr: char) -> Self {
+        StrictError {
+            input,
+            expected: Some(Expected::Char(chr)),
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        // NOTE when alternatives fail at the same place, a description of the syntax is more
+        // helpful than the first character of one of them
+        let rank = |err: &Self| match err.expected {
+            None => 0,
+            Some(Expected::Char(_)) => 1,
+            Some(Expected::Syntax(_)) => 2,
+        };
+        let ord = other
+            .input
+            .len()
+            .cmp(&self.input.len())
+            .then_with(|| rank(&self).cmp(&rank(&other)));
+        if ord == Ordering::Less {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl<'a> ContextError<&'a str> for StrictError<'a> {
+    fn add_context(_: &'a str, ctx: &'static str, other: Self) -> Self {
+        StrictError {
+            input: other.input,
+            expected: other.expected.or(Some(Expected::Syntax(ctx))),
+        }
+    }
+}
+
+/// A malformed prompt string, found by [`check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    offset: usize,
+    expected: Expected,
+}
+
+impl ParseError {
+    /// The byte offset into the prompt where parsing failed
+    #[must_use]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// What was expected at the offset
+    #[must_use]
+    pub fn expected(&self) -> Expected {
+        self.expected
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} at byte {}", self.expected, self.offset)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// An error expanding a prompt
+#[derive(Debug)]
+pub enum Error {
+    /// There was a problem writing the expansion
+    Io(io::Error),
+    /// A macro was used that isn't defined
+    UndefinedMacro {
+        /// The name of the undefined macro
+        name: String,
+        /// The macros being expanded where it was used, outermost first
+        within: Vec<String>,
+    },
+    /// A macro was used within its own expansion
+    MacroCycle(
+        /// The macros in the cycle, starting and ending with the same one
+        Vec<String>,
+    ),
+    /// A prompt or macro definition is malformed, see [`Prompt::parse_with_macros`]
+    Malformed {
+        /// Where it's malformed
+        err: ParseError,
+        /// The macros being expanded where it's malformed, outermost first, or empty if it's the
+        /// prompt itself
+        within: Vec<String>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::UndefinedMacro { name, within } => {
+                write!(f, "undefined macro \"{name}\"")?;
+                if let Some(parent) = within.last() {
+                    write!(f, " in macro \"{parent}\"")?;
+                }
+                Ok(())
+            }
+            Error::MacroCycle(names) => {
+                write!(f, "macro \"{}\" expands to itself: ", names[0])?;
+                write!(f, "{}", names.join(" -> "))
+            }
+            Error::Malformed { err, within } => {
+                write!(f, "{err}")?;
+                if let Some(parent) = within.last() {
+                    write!(f, " in macro \"{parent}\"")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Malformed { err, .. } => Some(err),
+            Error::UndefinedMacro { .. } | Error::MacroCycle(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
 /// The domain of the upstream remote, defaults to [`Domain::Git`]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -256,11 +724,80 @@ pub enum Domain {
     Azure = 4,
 }
 
+/// The kind of version control system a directory is in, defaults to [`Vcs::None`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Vcs {
+    /// Not in a repository
+    None = 0,
+    /// Git
+    Git = 1,
+    /// Mercurial
+    Hg = 2,
+    /// Jujutsu, including repositories colocated with git
+    Jj = 3,
+    /// Subversion
+    Svn = 4,
+    /// Fossil
+    Fossil = 5,
+}
+
+impl Vcs {
+    /// The name of the command line tool, or nothing if not in a repository
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Vcs::None => "",
+            Vcs::Git => "git",
+            Vcs::Hg => "hg",
+            Vcs::Jj => "jj",
+            Vcs::Svn => "svn",
+            Vcs::Fossil => "fossil",
+        }
+    }
+}
+
+/// The expensive information a prompt needs, as found by [`needs`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Needs {
+    /// Whether the git repo is dirty, has modified files, or has staged files
+    pub status: bool,
+    /// The remote domain, and the commits ahead and behind the remote
+    pub remote: bool,
+    /// The number of stashes
+    pub stashes: bool,
+    /// The name of the current branch
+    pub branch: bool,
+    /// The current jujutsu change
+    pub change: bool,
+}
+
+impl BitOr for Needs {
+    type Output = Needs;
+
+    fn bitor(self, other: Needs) -> Needs {
+        Needs {
+            status: self.status || other.status,
+            remote: self.remote || other.remote,
+            stashes: self.stashes || other.stashes,
+            branch: self.branch || other.branch,
+            change: self.change || other.change,
+        }
+    }
+}
+
+impl BitOrAssign for Needs {
+    fn bitor_assign(&mut self, other: Needs) {
+        *self = *self | other;
+    }
+}
+
 /// Trait for any information necessary to proper expansion
 pub trait Info {
     /// Get the current path for display
     fn current_path(&mut self) -> &Path;
-    /// Return true if inside a git repository
+    /// Return true if inside a git repository, or a repository of any other kind
     fn git_exists(&mut self) -> bool;
     /// Return true if git repo is dirty
     fn git_dirty(&mut self) -> bool;
@@ -268,36 +805,156 @@ pub trait Info {
     fn git_modified(&mut self) -> bool;
     /// Return true if git repo has staged files
     fn git_staged(&mut self) -> bool;
+    /// Return true if the dirty, modified, and staged status of the git repo is known
+    ///
+    /// Implementations that give up on computing status should return false here, and then false
+    /// from the status methods.
+    fn git_status_known(&mut self) -> bool {
+        true
+    }
     /// Get remote domain of git repo
     fn git_remote_domain(&mut self) -> Domain;
     /// Get number of commits current branch is ahead of remote
     fn git_remote_ahead(&mut self) -> usize;
     /// Get number of commits current branch is behind remote
     fn git_remote_behind(&mut self) -> usize;
+    /// Return true if the remote domain, and commits ahead and behind are known
+    ///
+    /// Implementations that give up on computing remote information should return false here, and
+    /// then defaults from the remote methods.
+    fn git_remote_known(&mut self) -> bool {
+        true
+    }
     /// Get name of the current git branch
     fn git_branch(&mut self) -> &str;
+    /// Get the root of the git working directory, if any
+    fn git_root(&mut self) -> Option<&Path> {
+        None
+    }
     /// Get the number of current stashes
     fn git_stashes(&mut self) -> usize;
+    /// Get the kind of repository this is inside
+    ///
+    /// By default this is git whenever [`Info::git_exists`] is true.
+    fn vcs(&mut self) -> Vcs {
+        if self.git_exists() {
+            Vcs::Git
+        } else {
+            Vcs::None
+        }
+    }
+    /// Return true if inside a jujutsu repository, which may be colocated with git
+    fn jj_exists(&mut self) -> bool {
+        false
+    }
+    /// Get the short id of the current jujutsu change
+    // NOTE implementations borrow the id from themselves
+    #[allow(clippy::unnecessary_literal_bound)]
+    fn jj_change_id(&mut self) -> &str {
+        ""
+    }
+    /// Return true if the current jujutsu change is empty
+    fn jj_empty(&mut self) -> bool {
+        false
+    }
+    /// Return true if the current jujutsu change has conflicts
+    fn jj_conflict(&mut self) -> bool {
+        false
+    }
+    /// Get the value of an environment variable
+    fn env_var(&mut self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+    /// Get zsh style named directories to abbreviate paths with, as `(name, directory)` pairs
+    fn named_dirs(&mut self) -> &[(String, PathBuf)] {
+        &[]
+    }
+    /// Start computing information that will be needed
+    ///
+    /// This is called before a prompt is rendered with everything it could use, so that
+    /// independent information can be computed concurrently. The information is still requested
+    /// through the other methods when it's used.
+    fn prefetch(&mut self, needs: &Needs) {
+        let _ = needs;
+    }
 }
 
 trait Render {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()>;
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()>;
+
+    /// Add the information this could use when rendering
+    fn needs(&self, _: &mut Needs) {}
+}
+
+/// Get the information needed for a code shared by escapes and conditionals
+fn code_needs(code: char, num: Option<i64>) -> Needs {
+    match code {
+        'r' => Needs {
+            branch: true,
+            ..Needs::default()
+        },
+        'y' | 'm' | 's' => Needs {
+            status: true,
+            ..Needs::default()
+        },
+        'k' => Needs {
+            status: num.unwrap_or(0) == 0,
+            remote: num == Some(1),
+            ..Needs::default()
+        },
+        'o' | 'p' | 'q' => Needs {
+            remote: true,
+            ..Needs::default()
+        },
+        'x' => Needs {
+            stashes: true,
+            ..Needs::default()
+        },
+        'J' | 'E' | 'X' => Needs {
+            change: true,
+            ..Needs::default()
+        },
+        _ => Needs::default(),
+    }
 }
 
 impl Render for Escape {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
         match self {
             Escape('r') => write!(out, "{}", info.git_branch()),
             Escape('p') => write!(out, "{}", info.git_remote_ahead()),
             Escape('q') => write!(out, "{}", info.git_remote_behind()),
             Escape('x') => write!(out, "{}", info.git_stashes()),
-            Escape(chr) => write!(out, "%{chr}"),
+            Escape('J') => write!(out, "{}", info.jj_change_id()),
+            Escape('R') => write!(out, "{}", info.vcs().name()),
+            Escape(chr) => {
+                if let Some(custom) = ext.escapes.get(chr) {
+                    write!(out, "{}", (custom.func)(info))
+                } else {
+                    write!(out, "%{chr}")
+                }
+            }
         }
     }
+
+    fn needs(&self, needs: &mut Needs) {
+        let Escape(chr) = self;
+        *needs |= code_needs(*chr, None);
+    }
 }
 
 impl Render for NumericEscape {
-    fn render(&self, out: &mut impl Write, _: &mut impl Info) -> io::Result<()> {
+    fn render(&self, out: &mut impl Write, _: &mut impl Info, _: &Extensions) -> io::Result<()> {
         match self {
             NumericEscape(Some(num), chr) => write!(out, "%{num}{chr}"),
             NumericEscape(None, chr) => write!(out, "%{chr}"),
@@ -306,14 +963,14 @@ impl Render for NumericEscape {
 }
 
 impl Render for DateFormat<'_> {
-    fn render(&self, out: &mut impl Write, _: &mut impl Info) -> io::Result<()> {
+    fn render(&self, out: &mut impl Write, _: &mut impl Info, _: &Extensions) -> io::Result<()> {
         let DateFormat(format) = self;
         write!(out, "%D{{{format}}}")
     }
 }
 
 impl Render for NamedColor<'_> {
-    fn render(&self, out: &mut impl Write, _: &mut impl Info) -> io::Result<()> {
+    fn render(&self, out: &mut impl Write, _: &mut impl Info, _: &Extensions) -> io::Result<()> {
         match self.num {
             Some(num) => write!(out, "%{}{}{{{}}}", num, self.code, self.name),
             None => write!(out, "%{}{{{}}}", self.code, self.name),
@@ -321,120 +978,304 @@ impl Render for NamedColor<'_> {
     }
 }
 
-impl Render for PathPrefix<'_> {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
-        let mut wd = info.current_path().to_owned();
-        for (alias, prefix) in &self.prefix_subs {
-            if let Ok(stripped) = wd.strip_prefix(prefix) {
-                wd = [alias.as_ref(), stripped].iter().collect();
+/// Remove the backslash escapes from an option
+fn unescape(raw: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = raw.chars();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\\' => unescaped.push(chars.next().unwrap_or('\\')),
+            chr => unescaped.push(chr),
+        }
+    }
+    unescaped
+}
+
+/// Remove backslash escapes from an option value, splitting it at the first unescaped `|`
+fn split_style(raw: &str) -> (String, String) {
+    let mut parts = [String::new(), String::new()];
+    let mut ind = 0;
+    let mut chars = raw.chars();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\\' => parts[ind].push(chars.next().unwrap_or('\\')),
+            '|' if ind == 0 => ind = 1,
+            chr => parts[ind].push(chr),
+        }
+    }
+    let [prefix, suffix] = parts;
+    (prefix, suffix)
+}
+
+struct PathComponent {
+    name: String,
+    root_dir: bool,
+    alias: bool,
+    repo: bool,
+}
+
+impl PathPrefix<'_> {
+    /// Parse a prefix, expanding variables in it if the `expand` option is set, and with
+    /// wildcards if the `glob` option is
+    fn pattern(&self, prefix: &str, info: &mut impl Info) -> Option<PathPattern> {
+        match (self.options.expand, self.options.glob) {
+            (true, glob) => PathPattern::parse_expanding(prefix, glob, |name| info.env_var(name)),
+            (false, true) => Some(PathPattern::parse(prefix)),
+            (false, false) => Some(PathPattern::literal(prefix)),
+        }
+    }
+
+    /// The alias for a match, which can only reference captures if the `glob` option is set
+    fn alias(&self, matched: &PrefixMatch, alias: &str) -> String {
+        if self.options.glob {
+            matched.expand(alias)
+        } else {
+            alias.to_owned()
+        }
+    }
+
+    /// Substitute prefixes in the current path
+    ///
+    /// Returns the new path, and the number of leading components that came from an alias.
+    fn substitute(&self, path: &Path, info: &mut impl Info) -> (PathBuf, usize) {
+        let mut candidates: Vec<_> = info
+            .named_dirs()
+            .iter()
+            .filter_map(|(name, dir)| Some((format!("~{name}"), path.strip_prefix(dir).ok()?)))
+            .collect();
+        if self.options.longest {
+            for (alias, prefix) in &self.prefix_subs {
+                let pattern = self.pattern(prefix, info);
+                if let Some(matched) = pattern.as_ref().and_then(|pat| pat.strip_prefix(path)) {
+                    candidates.push((self.alias(&matched, alias), matched.rest()));
+                }
+            }
+        }
+        // NOTE like zsh, the longest matching prefix wins, which leaves the shortest remainder, and
+        // earlier candidates break ties
+        let (mut wd, mut alias_len) = match candidates
+            .into_iter()
+            .min_by_key(|(_, rest)| rest.as_os_str().len())
+        {
+            Some((alias, rest)) => (
+                [Path::new(&alias), rest].iter().collect(),
+                Path::new(&alias).components().count(),
+            ),
+            None => (path.to_owned(), 0),
+        };
+        if !self.options.longest {
+            for (alias, prefix) in &self.prefix_subs {
+                let pattern = self.pattern(prefix, info);
+                if let Some(matched) = pattern.as_ref().and_then(|pat| pat.strip_prefix(&wd)) {
+                    let alias = self.alias(&matched, alias);
+                    let consumed = wd.components().count() - matched.rest().components().count();
+                    alias_len =
+                        Path::new(&alias).components().count() + alias_len.saturating_sub(consumed);
+                    wd = [Path::new(&alias), matched.rest()].iter().collect();
+                }
             }
         }
+        (wd, alias_len)
+    }
+}
+
+impl Render for PathPrefix<'_> {
+    fn render(&self, out: &mut impl Write, info: &mut impl Info, _: &Extensions) -> io::Result<()> {
+        let path = info.current_path().to_owned();
+        let (wd, alias_len) = self.substitute(&path, info);
+        let wd_len = wd.components().count();
+        // NOTE components after the aliases are always the tail of the original path, so we can
+        // find the repository root by its depth, and an alias that ends at the root counts too
+        let repo_ind = match self.options.repo {
+            Some(_) => info
+                .git_root()
+                .filter(|root| path.starts_with(root))
+                .and_then(|root| {
+                    (root.components().count() + wd_len).checked_sub(path.components().count() + 1)
+                })
+                .filter(|ind| ind + 1 >= alias_len),
+            None => None,
+        };
+        let mut comps: Vec<_> = wd
+            .components()
+            .enumerate()
+            .map(|(ind, comp)| PathComponent {
+                name: comp.as_os_str().to_string_lossy().into_owned(),
+                root_dir: comp == Component::RootDir,
+                alias: ind < alias_len,
+                repo: Some(ind) == repo_ind,
+            })
+            .collect();
         match self.num.unwrap_or(0) {
             0 => (),
             num @ 1..=i64::MAX => {
-                let mut comps = VecDeque::new();
-                for comp in &wd {
-                    comps.push_back(comp);
-                    if comps.len() == (num + 1).try_into().unwrap_or(usize::MAX) {
-                        comps.pop_front();
-                    }
-                }
-                wd = comps.iter().collect();
+                let keep = num.try_into().unwrap_or(usize::MAX);
+                comps.drain(..comps.len().saturating_sub(keep));
             }
             num @ i64::MIN..=-1 => {
-                let mut comps = Vec::new();
-                for comp in &wd {
-                    comps.push(comp);
-                    if comps.len() == (-num).try_into().unwrap_or(usize::MAX) {
-                        break;
-                    }
-                }
-                wd = comps.iter().collect();
+                comps.truncate((-num).try_into().unwrap_or(usize::MAX));
+            }
+        }
+        let sep = self.options.sep.as_deref().map(unescape);
+        let styles = [
+            self.options.alias.as_deref().map(split_style),
+            self.options.repo.as_deref().map(split_style),
+            self.options.root.as_deref().map(split_style),
+            self.options.last.as_deref().map(split_style),
+        ];
+        let last = comps.len().saturating_sub(1);
+        let mut prev_root_dir = false;
+        for (ind, comp) in comps.iter().enumerate() {
+            match &sep {
+                _ if ind == 0 => (),
+                Some(sep) => write!(out, "{sep}")?,
+                None if prev_root_dir => (),
+                None => write!(out, "{}", path::MAIN_SEPARATOR)?,
+            }
+            prev_root_dir = comp.root_dir;
+            let applies = [comp.alias, comp.repo, ind == 0, ind == last];
+            let active: Vec<_> = styles
+                .iter()
+                .zip(applies)
+                .filter_map(|(style, applies)| style.as_ref().filter(|_| applies))
+                .collect();
+            for (prefix, _) in &active {
+                write!(out, "{prefix}")?;
+            }
+            write!(out, "{}", comp.name)?;
+            for (_, suffix) in active.iter().rev() {
+                write!(out, "{suffix}")?;
             }
         }
-        let lossy = wd.to_string_lossy();
-        let mut lossy_chars = lossy.chars();
-        let output =
-            if lossy_chars.next() == Some(path::MAIN_SEPARATOR) && lossy_chars.next().is_none() {
-                &lossy
-            } else {
-                lossy.strip_suffix(path::MAIN_SEPARATOR).unwrap_or(&lossy)
-            };
-        write!(out, "{output}")
+        Ok(())
     }
 }
 
 impl Render for EscapeLiteral<'_> {
-    fn render(&self, out: &mut impl Write, _: &mut impl Info) -> io::Result<()> {
+    fn render(&self, out: &mut impl Write, _: &mut impl Info, _: &Extensions) -> io::Result<()> {
         let EscapeLiteral(literal) = self;
         write!(out, "%{{{literal}%}}")
     }
 }
 
 impl<T: Render> Render for [T] {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
         for elem in self {
-            elem.render(out, info)?;
+            elem.render(out, info, ext)?;
         }
         Ok(())
     }
+
+    fn needs(&self, needs: &mut Needs) {
+        for elem in self {
+            elem.needs(needs);
+        }
+    }
 }
 
-impl Render for Conditional<'_> {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
-        match self.code {
-            code @ ('G' | 'y' | 'm' | 's' | 'o' | 'p' | 'q' | 'x') => {
-                let num = self.num.unwrap_or(0);
-                if match code {
-                    'G' => info.git_exists(),
-                    'y' => info.git_dirty(),
-                    'm' => info.git_modified(),
-                    's' => info.git_staged(),
-                    'o' => info.git_remote_domain() as i64 == num,
-                    'p' => info.git_remote_ahead().try_into().unwrap_or(i64::MAX) >= num,
-                    'q' => info.git_remote_behind().try_into().unwrap_or(i64::MAX) >= num,
-                    'x' => info.git_stashes().try_into().unwrap_or(i64::MAX) >= num,
-                    _ => panic!(),
-                } {
-                    self.true_branch.render(out, info)
-                } else {
-                    self.false_branch.render(out, info)
-                }
-            }
+impl Conditional<'_> {
+    /// Evaluate the condition, or `None` if it's a zsh condition
+    fn test(&self, info: &mut impl Info, ext: &Extensions) -> Option<bool> {
+        let num = self.num.unwrap_or(0);
+        Some(match self.code {
+            'G' => info.git_exists(),
+            'k' => match num {
+                0 => info.git_status_known(),
+                1 => info.git_remote_known(),
+                _ => false,
+            },
+            'y' => info.git_dirty(),
+            'm' => info.git_modified(),
+            's' => info.git_staged(),
+            'o' => info.git_remote_domain() as i64 == num,
+            'p' => info.git_remote_ahead().try_into().unwrap_or(i64::MAX) >= num,
+            'q' => info.git_remote_behind().try_into().unwrap_or(i64::MAX) >= num,
+            'x' => info.git_stashes().try_into().unwrap_or(i64::MAX) >= num,
+            'J' => info.jj_exists(),
+            'E' => info.jj_empty(),
+            'X' => info.jj_conflict(),
+            'R' => info.vcs() as i64 == num,
             code => {
+                return ext
+                    .conditionals
+                    .get(&code)
+                    .map(|custom| (custom.func)(info, num))
+            }
+        })
+    }
+}
+
+impl Render for Conditional<'_> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
+        match self.test(info, ext) {
+            Some(true) => self.true_branch.render(out, info, ext),
+            Some(false) => self.false_branch.render(out, info, ext),
+            None => {
                 write!(out, "%")?;
                 if let Some(num) = self.num {
                     write!(out, "{num}")?;
-                };
-                write!(out, "({code}{}", self.delim)?;
-                self.true_branch.render(out, info)?;
+                }
+                write!(out, "({}{}", self.code, self.delim)?;
+                self.true_branch.render(out, info, ext)?;
                 write!(out, "{}", self.delim)?;
-                self.false_branch.render(out, info)?;
+                self.false_branch.render(out, info, ext)?;
                 write!(out, ")")
             }
         }
     }
+
+    fn needs(&self, needs: &mut Needs) {
+        *needs |= code_needs(self.code, self.num);
+        self.true_branch.needs(needs);
+        self.false_branch.needs(needs);
+    }
 }
 
-impl Render for AdvancedConditional<'_> {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
+impl AdvancedConditional<'_> {
+    /// Get the index of the condition to render
+    fn index(&self, info: &mut impl Info, ext: &Extensions) -> usize {
         let ind = match self.code {
             'o' => info.git_remote_domain() as usize,
             'p' => info.git_remote_ahead(),
             'q' => info.git_remote_behind(),
             'x' => info.git_stashes(),
-            _ => panic!(),
+            'R' => info.vcs() as usize,
+            code => (ext.advanced_conditionals[&code].func)(info),
         };
-        if ind < self.conditions.len() {
-            self.conditions[ind].render(out, info)
-        } else {
-            self.conditions.last().unwrap().render(out, info)
+        ind.min(self.conditions.len() - 1)
+    }
+}
+
+impl Render for AdvancedConditional<'_> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
+        self.conditions[self.index(info, ext)].render(out, info, ext)
+    }
+
+    fn needs(&self, needs: &mut Needs) {
+        *needs |= code_needs(self.code, None);
+        for condition in &self.conditions {
+            condition.needs(needs);
         }
     }
 }
 
 impl Render for Truncation<'_> {
-    fn render(&self, out: &mut impl Write, _: &mut impl Info) -> io::Result<()> {
+    fn render(&self, out: &mut impl Write, _: &mut impl Info, _: &Extensions) -> io::Result<()> {
         match self.num {
             Some(num) => write!(
                 out,
@@ -446,19 +1287,56 @@ impl Render for Truncation<'_> {
     }
 }
 
+impl Render for Macro<'_> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
+        match &self.body {
+            Some(body) => body.render(out, info, ext),
+            // NOTE without macros we leave them alone like other unknown expansions
+            None => write!(out, "%{{@{}}}", self.name),
+        }
+    }
+
+    fn needs(&self, needs: &mut Needs) {
+        if let Some(body) = &self.body {
+            body.needs(needs);
+        }
+    }
+}
+
 impl Render for Element<'_> {
-    fn render(&self, out: &mut impl Write, info: &mut impl Info) -> io::Result<()> {
+    fn render(
+        &self,
+        out: &mut impl Write,
+        info: &mut impl Info,
+        ext: &Extensions,
+    ) -> io::Result<()> {
         match self {
             Element::Character(chr) => write!(out, "{chr}"),
-            Element::Escape(esc) => esc.render(out, info),
-            Element::NumericEscape(num_esc) => num_esc.render(out, info),
-            Element::DateFormat(dfmt) => dfmt.render(out, info),
-            Element::NamedColor(color) => color.render(out, info),
-            Element::EscapeLiteral(esc) => esc.render(out, info),
-            Element::Conditional(cond) => cond.render(out, info),
-            Element::AdvancedConditional(cond) => cond.render(out, info),
-            Element::Truncation(trunc) => trunc.render(out, info),
-            Element::PathPrefix(path) => path.render(out, info),
+            Element::Escape(esc) => esc.render(out, info, ext),
+            Element::NumericEscape(num_esc) => num_esc.render(out, info, ext),
+            Element::DateFormat(dfmt) => dfmt.render(out, info, ext),
+            Element::NamedColor(color) => color.render(out, info, ext),
+            Element::EscapeLiteral(esc) => esc.render(out, info, ext),
+            Element::Conditional(cond) => cond.render(out, info, ext),
+            Element::AdvancedConditional(cond) => cond.render(out, info, ext),
+            Element::Truncation(trunc) => trunc.render(out, info, ext),
+            Element::PathPrefix(path) => path.render(out, info, ext),
+            Element::Macro(mac) => mac.render(out, info, ext),
+        }
+    }
+
+    fn needs(&self, needs: &mut Needs) {
+        match self {
+            Element::Escape(esc) => esc.needs(needs),
+            Element::Conditional(cond) => cond.needs(needs),
+            Element::AdvancedConditional(cond) => cond.needs(needs),
+            Element::Macro(mac) => mac.needs(needs),
+            _ => (),
         }
     }
 }
@@ -466,32 +1344,149 @@ impl Render for Element<'_> {
 /// Parses the input into a vector of elements
 ///
 /// This is the intermediate representation before re-rendering.
-fn parse(input: &str) -> Vec<Element> {
+fn parse<'a>(
+    input: &'a str,
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+) -> Vec<Element<'a>> {
     // NOTE unwrap should be safe because we always accept an arbitrary character
-    let (rem, elems) = many0(element)(input).finish().unwrap();
+    let (rem, elems) = many0(|inp| element::<false, nom::error::Error<_>>(ext, macros, inp))(input)
+        .finish()
+        .unwrap();
     // NOTE should also be safe for same reason
     assert_eq!(rem, "");
     elems
 }
 
-/// Expand a shibuichi prompt string
+/// Check that a shibuichi prompt string is well formed
 ///
-/// A shibuichi prompt string is a superset of [zsh prompt
-/// expansions](https://zsh.sourceforge.io/Doc/Release/Prompt-Expansion.html). Standard zsh prompt
-/// expansions are left alone, and this just adds several other expansions:
+/// Normally malformed expansions, like an unterminated conditional, are output as is, which zsh
+/// will likely do as well. This instead requires every `%` to start a valid expansion, and returns
+/// where parsing failed, and what was expected there.
 ///
-/// - `%r` - The short name of the current git branch. If not in a git repository this will be
-///   empty.
-/// - `%p` - An integer for the number of commits the current branch is *ahead* of its remote
+/// # Errors
+///
+/// If the prompt is malformed.
+pub fn check(prompt: impl AsRef<str>) -> Result<(), ParseError> {
+    parse_strict(prompt.as_ref(), &Extensions::default(), None).map(drop)
+}
+
+/// Parses the input into a vector of elements, requiring it to be well formed
+fn parse_strict<'a>(
+    prompt: &'a str,
+    ext: &Extensions,
+    macros: Option<&BTreeMap<String, String>>,
+) -> Result<Vec<Element<'a>>, ParseError> {
+    let mut elems = Vec::new();
+    let mut input = prompt;
+    while !input.is_empty() {
+        match element::<true, StrictError>(ext, macros, input) {
+            Ok((rest, elem)) => {
+                elems.push(elem);
+                input = rest;
+            }
+            Err(nom::Err::Error(err) | nom::Err::Failure(err)) => {
+                return Err(ParseError {
+                    offset: prompt.len() - err.input.len(),
+                    expected: err
+                        .expected
+                        .unwrap_or(Expected::Syntax("a valid expansion")),
+                })
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+    Ok(elems)
+}
+
+/// Replace every macro in `elems` with its parsed definition
+///
+/// `within` is the stack of macros currently being expanded, which is used to detect cycles. When
+/// parsing strictly, malformed definitions are errors.
+fn resolve<'a, const STRICT: bool>(
+    elems: &mut [Element<'a>],
+    macros: &'a BTreeMap<String, String>,
+    ext: &Extensions,
+    within: &mut Vec<&'a str>,
+) -> Result<(), Error> {
+    for elem in elems {
+        match elem {
+            Element::Conditional(cond) => {
+                resolve::<STRICT>(&mut cond.true_branch, macros, ext, within)?;
+                resolve::<STRICT>(&mut cond.false_branch, macros, ext, within)?;
+            }
+            Element::AdvancedConditional(cond) => {
+                for condition in &mut cond.conditions {
+                    resolve::<STRICT>(condition, macros, ext, within)?;
+                }
+            }
+            Element::Macro(mac) => {
+                if let Some(start) = within.iter().position(|name| *name == mac.name) {
+                    let mut cycle: Vec<_> = within[start..]
+                        .iter()
+                        .map(|&name| name.to_owned())
+                        .collect();
+                    cycle.push(mac.name.to_string());
+                    return Err(Error::MacroCycle(cycle));
+                }
+                let Some((name, def)) = macros.get_key_value(&*mac.name) else {
+                    return Err(Error::UndefinedMacro {
+                        name: mac.name.to_string(),
+                        within: within.iter().map(|&name| name.to_owned()).collect(),
+                    });
+                };
+                within.push(name);
+                let mut body = if STRICT {
+                    parse_strict(def, ext, Some(macros)).map_err(|err| Error::Malformed {
+                        err,
+                        within: within.iter().map(|&name| name.to_owned()).collect(),
+                    })?
+                } else {
+                    parse(def, ext, Some(macros))
+                };
+                resolve::<STRICT>(&mut body, macros, ext, within)?;
+                within.pop();
+                mac.body = Some(body);
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Find the expensive information expanding a shibuichi prompt string could use
+///
+/// This is conservative, every branch of a conditional is included even though only one will be
+/// rendered.
+pub fn needs(prompt: impl AsRef<str>) -> Needs {
+    let mut needs = Needs::default();
+    parse(prompt.as_ref(), &Extensions::default(), None).needs(&mut needs);
+    needs
+}
+
+/// Expand a shibuichi prompt string
+///
+/// A shibuichi prompt string is a superset of [zsh prompt
+/// expansions](https://zsh.sourceforge.io/Doc/Release/Prompt-Expansion.html). Standard zsh prompt
+/// expansions are left alone, and this just adds several other expansions:
+///
+/// - `%r` - The short name of the current git branch. If not in a git repository this will be
+///   empty. In mercurial this is the active bookmark or the branch, and in jujutsu the bookmarks
+///   on the current change.
+/// - `%p` - An integer for the number of commits the current branch is *ahead* of its remote
 ///   tracking branch. If there is no remote tracking branch, this will render as a 0.
 /// - `%q` - An integer for the number of commits the current branch is *behind* of its remote
 ///   tracking branch. If there is no remote tracking branch, this will render as a 0.
 /// - `%x` - An integer for the number of current stashes.
+/// - `%J` - The short id of the current jujutsu change. If not in a jujutsu repository this will
+///   be empty.
+/// - `%R` - The name of the version control system, e.g. `git` or `hg`. If not in a repository
+///   this will be empty.
 ///
 /// In addition, this also adds a few more codes to the conditional substring expansion
 /// `%(x.true-text.false-text)`. These codes are:
 ///
-/// - `G` - True if in a git repository.
+/// - `G` - True if in a repository of any kind, see `R` to tell them apart.
 /// - `y` - True if the git repository is dirty.
 /// - `m` - True if the git repository has modified files.
 /// - `s` - True if the git repository has staged files.
@@ -505,15 +1500,58 @@ fn parse(input: &str) -> Vec<Element> {
 /// - `q` - True if the remote tracking branch is at least `n` commits *behind* of the current
 ///   branch.
 /// - `x` - True if there are at least `n` stashes.
+/// - `J` - True if in a jujutsu repository, including one colocated with git.
+/// - `E` - True if the current jujutsu change is empty.
+/// - `X` - True if the current jujutsu change has conflicts.
+/// - `R` - True if the kind of version control system matches `n`, where 0 is not in a
+///   repository (see [Vcs]):
+///    1. git
+///    2. mercurial
+///    3. jujutsu
+///    4. subversion
+///    5. fossil
+/// - `k` - True if the information for `n` is known. Expensive information may be unknown if the
+///   [Info] gave up on computing it, in which case the expansions above act as if the repository
+///   is clean and up to date. The information is:
+///
+///   0. status: `y`, `m`, and `s`
+///   1. remote: `o`, `p`, and `q`, as well as `%p` and `%q`
 ///
 /// Finally the directory command is extended in a slightly breaking change, where
 ///
 /// - `%d{:replacement:prefix:...}`
 /// - `%/{:replacement:prefix:...}` - takes multiple prefix-replacement pairs to apply to the path.
-///   Any delimiter character can be specified, and backslash escapes are honored, but no other
-///   expansion happens. `%~` and `%/{:~:$HOME}` should be roughly equivalent. Note, that this
-///   tries the `PWD` variable first, and if it's missing uses a canonical working directory, which
-///   may be different than that output by `%/`.
+///   Any delimiter character can be specified, and backslash escapes are honored. Prefixes are
+///   taken literally, unless the `expand` option is given, in which case `$NAME`, `${NAME}` and a
+///   leading `~` are expanded from the environment (see [`Info::env_var`]), so this works even when
+///   the prompt is single quoted, and a pair referencing an unset variable is skipped. No other
+///   expansion happens. `%~` and `%/{[expand]:~:$HOME}` should be roughly equivalent. Note, that
+///   this tries the `PWD` variable first, and if it's missing uses a canonical working directory,
+///   which may be different than that output by `%/`. Any named directories (see
+///   [`Info::named_dirs`]) are substituted as `~name` before the explicit pairs, using the longest
+///   match like `%~` does.
+///
+///   Options can be given in brackets before the delimiter, e.g. `%/{[longest,expand]:~:$HOME}`.
+///   With `longest`, instead of applying every pair in order, all prefixes and named directories
+///   are compared against the original path and only the longest match is substituted, so
+///   `%/{[longest,expand]:~:$HOME:W:$HOME/work}` will abbreviate `$HOME/work` as `W`.
+///
+///   Individual components can be styled by passing `root`, `last`, `repo` or `alias` options with
+///   text to put before and after the first displayed component, the last one, the root of the git
+///   repository, or the components that were substituted with an alias. The text before and after
+///   are separated by `|`, e.g. `%/{[expand,last=%B|%b,repo=%F{red}|%f]:~:$HOME}`. Styles are
+///   nested with `alias` outermost, then `repo`, `root`, and `last`. The `sep` option replaces
+///   the path separator verbatim, and is placed between every pair of components including after
+///   the root directory. Backslash escapes `,`, `]` and `|` within options.
+///
+///   With `glob`, prefixes are glob patterns (see [`util::PathPattern`]), where `*` and `?` match
+///   within a path component and `**` matches any number of components. Each wildcard is
+///   captured, and can be referenced in the replacement as `\1` through `\9`, e.g.
+///   `%/{[glob]|\1:\2|/src/teams/*/services/*}` turns `/src/teams/core/services/api/bin` into
+///   `core:api/bin`. Escape a wildcard with a backslash to match it literally. Without it
+///   prefixes and replacements are used as is.
+///
+/// Before rendering, [`Info::prefetch`] is called with everything the prompt could use.
 ///
 /// # Errors
 ///
@@ -525,17 +1563,192 @@ pub fn expand(
 ) -> io::Result<()> {
     // NOTE if we use fold_many0 we could avoid this outer vector allocation, but then it would
     // require much better io error handling
-    let elems = parse(prompt.as_ref());
-    elems.render(out, info)
+    let ext = Extensions::default();
+    let elems = parse(prompt.as_ref(), &ext, None);
+    let mut needs = Needs::default();
+    elems.needs(&mut needs);
+    info.prefetch(&needs);
+    elems.render(out, info, &ext)
+}
+
+/// Find the expensive information expanding a shibuichi prompt string with macros could use
+///
+/// See [`needs`] and [`expand_with_macros`].
+///
+/// # Errors
+///
+/// If a macro is undefined or expands to itself.
+pub fn needs_with_macros(
+    prompt: impl AsRef<str>,
+    macros: &BTreeMap<String, String>,
+) -> Result<Needs, Error> {
+    let ext = Extensions::default();
+    let mut elems = parse(prompt.as_ref(), &ext, Some(macros));
+    resolve::<false>(&mut elems, macros, &ext, &mut Vec::new())?;
+    let mut needs = Needs::default();
+    elems.needs(&mut needs);
+    Ok(needs)
+}
+
+/// Expand a shibuichi prompt string with macros
+///
+/// This is the same as [`expand`] except that `%{@name}` is replaced by the expansion of the
+/// macro `name` from `macros`. Macros can use other macros, but not themselves.
+///
+/// # Errors
+///
+/// If a macro is undefined or expands to itself, or when there are problems writing to `out`.
+pub fn expand_with_macros(
+    prompt: impl AsRef<str>,
+    macros: &BTreeMap<String, String>,
+    info: &mut impl Info,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let ext = Extensions::default();
+    let mut elems = parse(prompt.as_ref(), &ext, Some(macros));
+    resolve::<false>(&mut elems, macros, &ext, &mut Vec::new())?;
+    let mut needs = Needs::default();
+    elems.needs(&mut needs);
+    info.prefetch(&needs);
+    Ok(elems.render(out, info, &ext)?)
+}
+
+/// Describe how a shibuichi prompt string with macros is parsed, and what it expands to
+///
+/// Every shibuichi expansion is annotated with what it means and its current value according to
+/// `info`, and everything else is passed through as text or zsh expansions. See [`ast::Node`].
+///
+/// # Errors
+///
+/// If a macro is undefined or expands to itself.
+pub fn explain(
+    prompt: impl AsRef<str>,
+    macros: &BTreeMap<String, String>,
+    info: &mut impl Info,
+) -> Result<Vec<ast::Node>, Error> {
+    let ext = Extensions::default();
+    let mut elems = parse(prompt.as_ref(), &ext, Some(macros));
+    resolve::<false>(&mut elems, macros, &ext, &mut Vec::new())?;
+    let mut needs = Needs::default();
+    elems.needs(&mut needs);
+    info.prefetch(&needs);
+    Ok(ast::nodes(&elems, info, &ext))
+}
+
+/// A parsed shibuichi prompt string
+///
+/// [`expand`] parses the prompt every time, while this can be parsed once and rendered repeatedly,
+/// e.g. in a long running program. Unlike [`expand`], parsing is strict, see [`check`].
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    elems: Vec<Element<'static>>,
+    needs: Needs,
+    extensions: Arc<Extensions>,
+}
+
+impl Prompt {
+    fn new(elems: Vec<Element<'_>>, extensions: Arc<Extensions>) -> Self {
+        let mut needs = Needs::default();
+        elems.needs(&mut needs);
+        Prompt {
+            elems: elems.into_iter().map(Element::into_owned).collect(),
+            needs,
+            extensions,
+        }
+    }
+
+    /// Parse a shibuichi prompt string
+    ///
+    /// Macros are left alone, as with [`expand`].
+    ///
+    /// # Errors
+    ///
+    /// If the prompt is malformed.
+    pub fn parse(prompt: &str) -> Result<Self, ParseError> {
+        let extensions = Arc::default();
+        let elems = parse_strict(prompt, &extensions, None)?;
+        Ok(Prompt::new(elems, extensions))
+    }
+
+    /// Parse a shibuichi prompt string with macros
+    ///
+    /// Macros are expanded as with [`expand_with_macros`], and their definitions must be well
+    /// formed too.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt or a macro definition is malformed, or a macro is undefined or expands to
+    /// itself.
+    pub fn parse_with_macros(
+        prompt: &str,
+        macros: &BTreeMap<String, String>,
+    ) -> Result<Self, Error> {
+        Prompt::parse_with_extensions(prompt, macros, Arc::default())
+    }
+
+    /// Parse a shibuichi prompt string with macros and custom codes
+    ///
+    /// This is the same as [`Prompt::parse_with_macros`], except that the codes registered in
+    /// `extensions` can be used too. They can be shared between prompts.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt or a macro definition is malformed, or a macro is undefined or expands to
+    /// itself.
+    pub fn parse_with_extensions(
+        prompt: &str,
+        macros: &BTreeMap<String, String>,
+        extensions: Arc<Extensions>,
+    ) -> Result<Self, Error> {
+        let mut elems =
+            parse_strict(prompt, &extensions, Some(macros)).map_err(|err| Error::Malformed {
+                err,
+                within: Vec::new(),
+            })?;
+        resolve::<true>(&mut elems, macros, &extensions, &mut Vec::new())?;
+        Ok(Prompt::new(elems, extensions))
+    }
+
+    /// The expensive information rendering could use, see [`needs`]
+    ///
+    /// Custom codes aren't included, since they compute what they need themselves.
+    #[must_use]
+    pub fn needs(&self) -> Needs {
+        self.needs
+    }
+
+    /// Render the prompt
+    ///
+    /// As with [`expand`], [`Info::prefetch`] is called first with everything the prompt could
+    /// use.
+    ///
+    /// # Errors
+    ///
+    /// When there are problems writing to `out`.
+    pub fn render(&self, info: &mut impl Info, out: &mut impl Write) -> io::Result<()> {
+        info.prefetch(&self.needs);
+        self.elems.render(out, info, &self.extensions)
+    }
+
+    /// Describe the prompt, see [`explain`]
+    pub fn explain(&self, info: &mut impl Info) -> Vec<ast::Node> {
+        info.prefetch(&self.needs);
+        ast::nodes(&self.elems, info, &self.extensions)
+    }
 }
 
 #[cfg(test)]
 mod parse_tests {
     use super::{
-        parse, AdvancedConditional, Conditional, DateFormat, Element, Escape, EscapeLiteral,
-        NamedColor, NumericEscape, PathPrefix, Truncation,
+        check, parse_strict, AdvancedConditional, Conditional, DateFormat, Element, Escape,
+        EscapeLiteral, Expected, Extensions, Macro, NamedColor, Needs, NumericEscape, PathOptions,
+        PathPrefix, Truncation,
     };
 
+    fn parse(input: &str) -> Vec<Element<'_>> {
+        super::parse(input, &Extensions::default(), None)
+    }
+
     #[test]
     fn simple_escapes() {
         let expected = [
@@ -553,7 +1766,7 @@ mod parse_tests {
 
     #[test]
     fn date_format() {
-        let expected = [Element::DateFormat(DateFormat("%H:%M:%S.%."))];
+        let expected = [Element::DateFormat(DateFormat("%H:%M:%S.%.".into()))];
         let elems = parse("%D{%H:%M:%S.%.}");
         assert_eq!(elems, expected);
     }
@@ -564,13 +1777,13 @@ mod parse_tests {
             Element::NamedColor(NamedColor {
                 num: None,
                 code: 'F',
-                name: "red",
+                name: "red".into(),
             }),
             Element::Character(' '),
             Element::NamedColor(NamedColor {
                 num: Some(0),
                 code: 'K',
-                name: "black",
+                name: "black".into(),
             }),
         ];
         let elems = parse("%F{red} %0K{black}");
@@ -583,6 +1796,7 @@ mod parse_tests {
             Element::PathPrefix(PathPrefix {
                 num: None,
                 code: 'd',
+                options: PathOptions::default(),
                 delim: '.',
                 prefix_subs: vec![],
             }),
@@ -590,39 +1804,155 @@ mod parse_tests {
             Element::PathPrefix(PathPrefix {
                 num: Some(-2),
                 code: '/',
+                options: PathOptions::default(),
+                delim: ':',
+                prefix_subs: vec![("home".into(), "/home/user".into())],
+            }),
+            Element::Character(' '),
+            Element::PathPrefix(PathPrefix {
+                num: None,
+                code: 'd',
+                options: PathOptions {
+                    longest: true,
+                    ..PathOptions::default()
+                },
+                delim: ':',
+                prefix_subs: vec![("~".into(), "$HOME".into())],
+            }),
+            Element::Character(' '),
+            Element::PathPrefix(PathPrefix {
+                num: None,
+                code: 'd',
+                options: PathOptions {
+                    sep: Some(" > ".into()),
+                    last: Some("%B|%b".into()),
+                    repo: Some("\\]".into()),
+                    ..PathOptions::default()
+                },
                 delim: ':',
-                prefix_subs: vec![("home", "/home/user")],
+                prefix_subs: vec![],
+            }),
+            Element::Character(' '),
+            Element::PathPrefix(PathPrefix {
+                num: None,
+                code: 'd',
+                options: PathOptions::default(),
+                delim: '[',
+                prefix_subs: vec![("a".into(), "b]".into())],
             }),
         ];
-        let elems = parse("%d{.} %-2/{:home:/home/user}");
+        let elems = parse(
+            "%d{.} %-2/{:home:/home/user} %d{[longest]:~:$HOME} %d{[sep= > ,last=%B|%b,repo=\\]]:} %d{[a[b]}",
+        );
         assert_eq!(elems, expected);
     }
 
     #[test]
     fn escape_literal() {
-        let expected = [Element::EscapeLiteral(EscapeLiteral("$terminfo[smacs]%G"))];
+        let expected = [Element::EscapeLiteral(EscapeLiteral(
+            "$terminfo[smacs]%G".into(),
+        ))];
         let elems = parse("%{$terminfo[smacs]%G%}");
         assert_eq!(elems, expected);
     }
 
+    #[test]
+    fn strict() {
+        let example = " %F{white}%K{black}%(?.%1(j. x .). %1(j..) ) %n@%m %/{::$HOME} %(G.%(y.%K{yellow}.%K{green}) %(o..a.b.c.d)%(p....%p)%(x....%x) %r%(m. .%(s. .)).)%k%f %{@m} %{%G%} %-3<..< %D{%H} %% %)";
+        assert_eq!(check(example), Ok(()));
+        assert_eq!(check(""), Ok(()));
+
+        let cases = [
+            ("%(y.a.b", 7, Expected::Char(')')),
+            ("%(o.a.b.c", 9, Expected::Char(')')),
+            ("%(Z.a.b)", 2, Expected::Syntax("a conditional code")),
+            ("%(y.%F{red.b)", 13, Expected::Char('}')),
+            ("%D{%H", 5, Expected::Char('}')),
+            ("%{abc", 2, Expected::Syntax("`%}`")),
+            ("%/{:a", 4, Expected::Char('}')),
+            ("%<..", 4, Expected::Char('<')),
+            ("ok %", 4, Expected::Syntax("an escape")),
+            ("%Z", 1, Expected::Syntax("an escape")),
+        ];
+        for (prompt, offset, expected) in cases {
+            let err = check(prompt).unwrap_err();
+            assert_eq!(
+                (err.offset(), err.expected()),
+                (offset, expected),
+                "{prompt}"
+            );
+        }
+        assert_eq!(
+            check("%(y.a.b").unwrap_err().to_string(),
+            "expected `)` at byte 7"
+        );
+    }
+
+    #[test]
+    fn macro_ref() {
+        let expected = [
+            Element::Macro(Macro {
+                name: "sep-1".into(),
+                body: None,
+            }),
+            Element::EscapeLiteral(EscapeLiteral("@sep%G".into())),
+        ];
+        let elems = parse("%{@sep-1}%{@sep%G%}");
+        assert_eq!(elems, expected);
+    }
+
+    #[test]
+    fn extensions() {
+        let mut ext = Extensions::default();
+        ext.escape('Z', "zone", |_| String::new())
+            .conditional('Z', "zoned", |_, _| true)
+            .advanced_conditional('z', "zones", |_| 0);
+        let expected = [
+            Element::Escape(Escape('Z')),
+            Element::Conditional(Conditional {
+                num: Some(2),
+                code: 'Z',
+                delim: '.',
+                true_branch: vec![Element::Character('a')],
+                false_branch: vec![],
+            }),
+            Element::AdvancedConditional(AdvancedConditional {
+                code: 'z',
+                delim: '.',
+                conditions: vec![vec![], vec![Element::Character('b')], vec![]],
+            }),
+        ];
+        let prompt = "%Z%2(Z.a.)%(z..b.)";
+        assert_eq!(super::parse(prompt, &ext, None), expected);
+        assert_eq!(parse_strict(prompt, &ext, None), Ok(expected.to_vec()));
+        assert_ne!(parse(prompt), expected);
+        assert!(check(prompt).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "%r is already an escape")]
+    fn reserved_extensions() {
+        Extensions::default().escape('r', "", |_| String::new());
+    }
+
     #[test]
     fn truncation() {
         let expected = [
             Element::Truncation(Truncation {
                 num: Some(8),
                 code: '<',
-                replacement: "..",
+                replacement: "..".into(),
             }),
             Element::Truncation(Truncation {
                 num: None,
                 code: '<',
-                replacement: "",
+                replacement: "".into(),
             }),
             Element::Character(' '),
             Element::Truncation(Truncation {
                 num: None,
                 code: '>',
-                replacement: "\\>",
+                replacement: "\\>".into(),
             }),
         ];
         let elems = parse("%8<..<%<< %>\\>>");
@@ -662,13 +1992,49 @@ mod parse_tests {
         let elems = parse("%(o.a.b.c)");
         assert_eq!(elems, expected);
     }
+
+    #[test]
+    fn needs() {
+        assert_eq!(super::needs("%~ %(?.a.b) %/{:x:y}"), Needs::default());
+        assert_eq!(
+            super::needs("%r %(x..%p)"),
+            Needs {
+                remote: true,
+                stashes: true,
+                branch: true,
+                ..Needs::default()
+            }
+        );
+        assert_eq!(
+            super::needs("%(G.%(y.a.b).%1(k.c.d))"),
+            Needs {
+                status: true,
+                remote: true,
+                ..Needs::default()
+            }
+        );
+        assert_eq!(
+            super::needs("%(o..%(m..).)"),
+            Needs {
+                status: true,
+                remote: true,
+                ..Needs::default()
+            }
+        );
+    }
 }
 
 #[cfg(test)]
 mod expand_tests {
-    use super::{expand, Domain, Info};
+    use super::ast::{write_tree, Node};
+    use super::{
+        expand, expand_with_macros, explain, needs_with_macros, Domain, Error, Expected,
+        Extensions, Info, Needs, Prompt, Vcs,
+    };
+    use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
     use std::str;
+    use std::sync::Arc;
 
     struct NoInfo;
 
@@ -698,7 +2064,7 @@ mod expand_tests {
         fn git_remote_behind(&mut self) -> usize {
             0
         }
-        fn git_branch(&mut self) -> &str {
+        fn git_branch(&mut self) -> &'static str {
             ""
         }
         fn git_stashes(&mut self) -> usize {
@@ -729,6 +2095,248 @@ mod expand_tests {
         assert_eq!(str::from_utf8(&result).unwrap(), "r a0 b0 s0");
     }
 
+    struct UnknownInfo;
+
+    impl Info for UnknownInfo {
+        fn current_path(&mut self) -> &Path {
+            "".as_ref()
+        }
+        fn git_exists(&mut self) -> bool {
+            true
+        }
+        fn git_dirty(&mut self) -> bool {
+            false
+        }
+        fn git_modified(&mut self) -> bool {
+            false
+        }
+        fn git_staged(&mut self) -> bool {
+            false
+        }
+        fn git_status_known(&mut self) -> bool {
+            false
+        }
+        fn git_remote_domain(&mut self) -> Domain {
+            Domain::Git
+        }
+        fn git_remote_ahead(&mut self) -> usize {
+            0
+        }
+        fn git_remote_behind(&mut self) -> usize {
+            0
+        }
+        fn git_remote_known(&mut self) -> bool {
+            false
+        }
+        fn git_branch(&mut self) -> &'static str {
+            "main"
+        }
+        fn git_stashes(&mut self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn known_conditionals() {
+        let prompt = "%(k.%(y.d.c).?) %1(k.%p.?) %2(k.k.u)";
+        let mut result = Vec::new();
+        expand(prompt, &mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "c 0 u");
+
+        result.clear();
+        expand(prompt, &mut UnknownInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "? ? u");
+    }
+
+    #[test]
+    fn macros() {
+        let macros: BTreeMap<_, _> = [
+            ("arrow", "%F{blue}%{@tip}%f"),
+            ("tip", ">"),
+            ("dirty", "%(y.%{@arrow}.)"),
+            ("loop", "a%{@cycle}"),
+            ("cycle", "%(y..%{@loop})"),
+            ("broken", "%{@missing}"),
+        ]
+        .into_iter()
+        .map(|(name, def)| (name.to_owned(), def.to_owned()))
+        .collect();
+
+        let mut result = Vec::new();
+        expand_with_macros(
+            "%{@arrow} %(G.%{@arrow}.)%{@dirty}",
+            &macros,
+            &mut NoInfo,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "%F{blue}>%f ");
+        // NOTE an undefined name followed by `%}` is a zsh literal
+        result.clear();
+        expand_with_macros(
+            "%{@x} y%}%{@tip}%{@foo} bar%}x",
+            &macros,
+            &mut NoInfo,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "%{@x} y%}>%{@foo} bar%}x");
+        assert!(Prompt::parse_with_macros("%{@x} y%}", &macros).is_ok());
+        assert_eq!(
+            needs_with_macros("%{@dirty}", &macros).unwrap(),
+            Needs {
+                status: true,
+                ..Needs::default()
+            }
+        );
+
+        let err = needs_with_macros("%{@loop}", &macros).unwrap_err();
+        assert!(matches!(&err, Error::MacroCycle(names) if names == &["loop", "cycle", "loop"]));
+        assert_eq!(
+            err.to_string(),
+            "macro \"loop\" expands to itself: loop -> cycle -> loop"
+        );
+        let err = needs_with_macros("x %{@broken}", &macros).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "undefined macro \"missing\" in macro \"broken\""
+        );
+
+        // NOTE without macros they're left alone
+        result.clear();
+        expand("%{@arrow}", &mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "%{@arrow}");
+    }
+
+    #[test]
+    fn compiled() {
+        let prompt = Prompt::parse("%r %(y.*.)%{@m}").unwrap();
+        assert_eq!(
+            prompt.needs(),
+            Needs {
+                status: true,
+                branch: true,
+                ..Needs::default()
+            }
+        );
+        let mut result = Vec::new();
+        prompt.render(&mut UnknownInfo, &mut result).unwrap();
+        prompt.render(&mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "main %{@m} %{@m}");
+        assert_eq!(
+            Prompt::parse("%(y.a.b").unwrap_err().expected(),
+            Expected::Char(')')
+        );
+
+        let macros: BTreeMap<_, _> = [
+            ("ok".to_owned(), "%(x.%x.)".to_owned()),
+            ("broken".to_owned(), "%F{red".to_owned()),
+        ]
+        .into();
+        let prompt = Prompt::parse_with_macros("%{@ok}", &macros).unwrap();
+        assert!(prompt.needs().stashes);
+        let err = Prompt::parse_with_macros("%{@broken}", &macros).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected `}` at byte 6 in macro \"broken\""
+        );
+        let err = Prompt::parse_with_macros("%{@ok", &macros).unwrap_err();
+        assert!(matches!(err, Error::Malformed { within, .. } if within.is_empty()));
+        assert!(matches!(
+            Prompt::parse_with_macros("%{@missing}", &macros),
+            Err(Error::UndefinedMacro { .. })
+        ));
+    }
+
+    #[test]
+    fn extended() {
+        let mut ext = Extensions::default();
+        ext.escape('Z', "branch length", |info| {
+            info.git_branch().len().to_string()
+        })
+        .conditional('Z', "branch is longer than n", |info, num| {
+            info.git_branch().len().try_into().unwrap_or(i64::MAX) > num
+        })
+        .advanced_conditional('z', "branch length", |info| info.git_branch().len());
+        let ext = Arc::new(ext);
+        let macros: BTreeMap<_, _> = [("long".to_owned(), "%3(Z.long.short)".to_owned())].into();
+        let prompt =
+            Prompt::parse_with_extensions("%Z %{@long} %(z.a.b.c.d.e)", &macros, ext.clone())
+                .unwrap();
+        let mut result = Vec::new();
+        prompt.render(&mut UnknownInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "4 long e");
+        result.clear();
+        prompt.render(&mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "0 short a");
+        assert_eq!(
+            prompt.explain(&mut UnknownInfo)[0],
+            Node::Escape {
+                code: 'Z',
+                meaning: "branch length",
+                value: "4".to_owned()
+            }
+        );
+
+        // NOTE without the extensions they're left alone
+        result.clear();
+        expand("%Z %3(Z.long.short)", &mut UnknownInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "%Z %3(Z.long.short)");
+        assert!(Prompt::parse_with_macros("%Z", &macros).is_err());
+    }
+
+    #[test]
+    fn explained() {
+        let macros: BTreeMap<_, _> = [("dirty".to_owned(), "%(y.*.)".to_owned())].into();
+        let nodes = explain("%n: %r%{@dirty} %2(q.b.)", &macros, &mut UnknownInfo).unwrap();
+        assert_eq!(
+            nodes[..3],
+            [
+                Node::Zsh {
+                    source: "%n".to_owned()
+                },
+                Node::Text {
+                    text: ": ".to_owned()
+                },
+                Node::Escape {
+                    code: 'r',
+                    meaning: "current git branch",
+                    value: "main".to_owned()
+                },
+            ]
+        );
+
+        let mut result = Vec::new();
+        write_tree(&nodes, &mut result).unwrap();
+        assert_eq!(
+            str::from_utf8(&result).unwrap(),
+            concat!(
+                "zsh \"%n\"\n",
+                "text \": \"\n",
+                "escape %r: current git branch = \"main\"\n",
+                "macro @dirty\n",
+                "  conditional %(y: repository is dirty = false\n",
+                "    true:\n",
+                "      text \"*\"\n",
+                "    false:\n",
+                "text \" \"\n",
+                "conditional %2(q: at least n commits behind the remote = false\n",
+                "  true:\n",
+                "    text \"b\"\n",
+                "  false:\n",
+            )
+        );
+        assert_eq!(
+            serde_json::to_string(&nodes[3]).unwrap(),
+            concat!(
+                r#"{"kind":"macro","name":"dirty","body":[{"kind":"conditional","code":"y","#,
+                r#""num":null,"meaning":"repository is dirty","value":false,"#,
+                r#""true_branch":[{"kind":"text","text":"*"}],"false_branch":[]}]}"#,
+            )
+        );
+        assert!(explain("%{@missing}", &macros, &mut NoInfo).is_err());
+    }
+
     #[test]
     fn empty_conditionals() {
         let mut result = Vec::new();
@@ -741,6 +2349,84 @@ mod expand_tests {
         assert_eq!(str::from_utf8(&result).unwrap(), "n nnn dnd an bn sn");
     }
 
+    struct JjInfo;
+
+    impl Info for JjInfo {
+        fn current_path(&mut self) -> &Path {
+            "".as_ref()
+        }
+        fn git_exists(&mut self) -> bool {
+            true
+        }
+        fn git_dirty(&mut self) -> bool {
+            true
+        }
+        fn git_modified(&mut self) -> bool {
+            true
+        }
+        fn git_staged(&mut self) -> bool {
+            false
+        }
+        fn git_remote_domain(&mut self) -> Domain {
+            Domain::Git
+        }
+        fn git_remote_ahead(&mut self) -> usize {
+            0
+        }
+        fn git_remote_behind(&mut self) -> usize {
+            0
+        }
+        fn git_branch(&mut self) -> &'static str {
+            "main"
+        }
+        fn git_stashes(&mut self) -> usize {
+            0
+        }
+        fn vcs(&mut self) -> Vcs {
+            Vcs::Jj
+        }
+        fn jj_exists(&mut self) -> bool {
+            true
+        }
+        fn jj_change_id(&mut self) -> &'static str {
+            "kntqzsqt"
+        }
+        fn jj_conflict(&mut self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn jj_expansions() {
+        let prompt = "%(J.%J %r.git)%(E. empty.)%(X. conflict.)";
+        let mut result = Vec::new();
+        expand(prompt, &mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "git");
+
+        result.clear();
+        let kinds = "[%R] %(R.none.git.hg.jj) %1(R.git.other)";
+        expand(kinds, &mut NoInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[] none other");
+        result.clear();
+        expand(kinds, &mut UnknownInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[git] git git");
+        result.clear();
+        expand(kinds, &mut JjInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[jj] jj other");
+
+        result.clear();
+        expand(prompt, &mut JjInfo, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "kntqzsqt main conflict");
+        assert_eq!(
+            needs_with_macros(prompt, &BTreeMap::new()).unwrap(),
+            Needs {
+                branch: true,
+                change: true,
+                ..Needs::default()
+            }
+        );
+    }
+
     struct TestInfo {
         path: PathBuf,
         dirty: bool,
@@ -751,6 +2437,7 @@ mod expand_tests {
         behind: usize,
         branch: &'static str,
         stashes: usize,
+        named_dirs: Vec<(String, PathBuf)>,
     }
 
     impl Info for TestInfo {
@@ -781,9 +2468,21 @@ mod expand_tests {
         fn git_branch(&mut self) -> &str {
             self.branch
         }
+        fn git_root(&mut self) -> Option<&Path> {
+            Some("/home/user/proj".as_ref())
+        }
         fn git_stashes(&mut self) -> usize {
             self.stashes
         }
+        fn env_var(&mut self, name: &str) -> Option<String> {
+            match name {
+                "HOME" => Some("/home/user".to_owned()),
+                _ => None,
+            }
+        }
+        fn named_dirs(&mut self) -> &[(String, PathBuf)] {
+            &self.named_dirs
+        }
     }
 
     #[test]
@@ -802,6 +2501,7 @@ mod expand_tests {
             behind: 1,
             branch: "main",
             stashes: 1,
+            named_dirs: Vec::new(),
         };
         expand(prompt, &mut info, &mut result).unwrap();
         assert_eq!(str::from_utf8(&result).unwrap(), "dms h ^2vs main");
@@ -817,6 +2517,7 @@ mod expand_tests {
             behind: 2,
             branch: "feature",
             stashes: 3,
+            named_dirs: Vec::new(),
         };
         expand(prompt, &mut info, &mut result).unwrap();
         assert_eq!(str::from_utf8(&result).unwrap(), "d a v2s3 feature");
@@ -835,6 +2536,7 @@ mod expand_tests {
             behind: 2,
             branch: "feature",
             stashes: 3,
+            named_dirs: Vec::new(),
         };
 
         result.clear();
@@ -854,6 +2556,241 @@ mod expand_tests {
         assert_eq!(str::from_utf8(&result).unwrap(), "sub/dir");
     }
 
+    #[test]
+    fn path_named_dirs() {
+        let mut result = Vec::new();
+        let mut info = TestInfo {
+            path: PathBuf::from("/home/user/proj/src/bin"),
+            dirty: false,
+            modified: false,
+            staged: false,
+            domain: Domain::Git,
+            ahead: 0,
+            behind: 0,
+            branch: "main",
+            stashes: 0,
+            named_dirs: vec![
+                ("other".to_owned(), PathBuf::from("/home/user/proj/sr")),
+                ("proj".to_owned(), PathBuf::from("/home/user/proj")),
+                ("src".to_owned(), PathBuf::from("/home/user/proj/src")),
+                ("dup".to_owned(), PathBuf::from("/home/user/proj/src")),
+            ],
+        };
+
+        result.clear();
+        expand("%/{:}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~src/bin");
+
+        result.clear();
+        expand("%/{:S:~src}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "S/bin");
+
+        result.clear();
+        info.path = PathBuf::from("/home/user/docs");
+        expand("%/{[expand]:~:$HOME}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~/docs");
+    }
+
+    #[test]
+    fn path_longest() {
+        let mut result = Vec::new();
+        let mut info = TestInfo {
+            path: PathBuf::from("/home/user/work/proj"),
+            dirty: false,
+            modified: false,
+            staged: false,
+            domain: Domain::Git,
+            ahead: 0,
+            behind: 0,
+            branch: "main",
+            stashes: 0,
+            named_dirs: Vec::new(),
+        };
+
+        result.clear();
+        expand("%/{[expand]:~:$HOME:W:$HOME/work}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~/work/proj");
+
+        result.clear();
+        expand(
+            "%/{[longest,expand]:~:$HOME:W:$HOME/work}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "W/proj");
+
+        result.clear();
+        expand(
+            "%/{[longest,expand]:~:$HOME:h:/home}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~/work/proj");
+
+        result.clear();
+        info.named_dirs = vec![("user".to_owned(), PathBuf::from("/home/user"))];
+        expand("%/{[longest]:h:/home}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~user/work/proj");
+
+        result.clear();
+        expand("%/{[longest]:x:/missing}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~user/work/proj");
+    }
+
+    #[test]
+    fn path_styles() {
+        let mut result = Vec::new();
+        let mut info = TestInfo {
+            path: PathBuf::from("/home/user/proj/src/bin"),
+            dirty: false,
+            modified: false,
+            staged: false,
+            domain: Domain::Git,
+            ahead: 0,
+            behind: 0,
+            branch: "main",
+            stashes: 0,
+            named_dirs: Vec::new(),
+        };
+
+        result.clear();
+        expand("%/{[sep= > ]:}", &mut info, &mut result).unwrap();
+        assert_eq!(
+            str::from_utf8(&result).unwrap(),
+            "/ > home > user > proj > src > bin"
+        );
+
+        result.clear();
+        expand(
+            "%/{[expand,root=<|>,last=(|),repo=%B|%b,alias=[|\\]]:~:$HOME}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[<~>]/%Bproj%b/src/(bin)");
+
+        result.clear();
+        expand(
+            "%-2/{[expand,repo=*|*,last=\\,|\\,]:P:~/proj}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "*P*/,src,");
+
+        result.clear();
+        expand("%2/{[repo=*|*,root=^]:}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "^src/bin");
+
+        result.clear();
+        info.path = PathBuf::from("/home/user/other");
+        expand(
+            "%/{[repo=*|*,root=^,sep=|]:x:/home}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "^x|user|other");
+
+        result.clear();
+        expand("%/{[sep=\\,\\|]:x:/home}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "x,|user,|other");
+    }
+
+    #[test]
+    fn path_variables() {
+        let mut result = Vec::new();
+        let mut info = TestInfo {
+            path: PathBuf::from("/home/user/sub/dir"),
+            dirty: false,
+            modified: false,
+            staged: false,
+            domain: Domain::Git,
+            ahead: 0,
+            behind: 0,
+            branch: "main",
+            stashes: 0,
+            named_dirs: Vec::new(),
+        };
+
+        result.clear();
+        expand("%/{[expand]:~:$HOME}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "~/sub/dir");
+
+        result.clear();
+        expand("%/{[expand]:s:${HOME}/sub:h:~}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "s/dir");
+
+        result.clear();
+        expand("%/{[expand]:m:$MISSING:h:\\~}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "/home/user/sub/dir");
+
+        // NOTE without the option prefixes are taken literally
+        result.clear();
+        expand("%/{:~:$HOME:h:~}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "/home/user/sub/dir");
+
+        result.clear();
+        info.path = PathBuf::from("/srv/$app/~/log");
+        expand("%/{:A:/srv/$app/~}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "A/log");
+    }
+
+    #[test]
+    fn path_pattern() {
+        let mut result = Vec::new();
+        let mut info = TestInfo {
+            path: PathBuf::from("/src/teams/core/services/api/bin"),
+            dirty: false,
+            modified: false,
+            staged: false,
+            domain: Domain::Git,
+            ahead: 0,
+            behind: 0,
+            branch: "main",
+            stashes: 0,
+            named_dirs: Vec::new(),
+        };
+
+        result.clear();
+        expand(
+            "%/{[glob]|\\1:\\2|/src/teams/*/services/*}",
+            &mut info,
+            &mut result,
+        )
+        .unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "core:api/bin");
+
+        result.clear();
+        expand("%/{[glob]:\\1\\:\\2:/src/**/api}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "teams/core/services:/bin");
+
+        result.clear();
+        expand("%/{[glob]:x:/src/\\*}", &mut info, &mut result).unwrap();
+        assert_eq!(
+            str::from_utf8(&result).unwrap(),
+            "/src/teams/core/services/api/bin"
+        );
+
+        result.clear();
+        expand("%-1/{[glob]:[\\1]:/src/t?ams}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "[e]");
+
+        // NOTE without the option wildcards and backslashes are matched literally
+        result.clear();
+        expand("%/{:\\1:/src/*}", &mut info, &mut result).unwrap();
+        assert_eq!(
+            str::from_utf8(&result).unwrap(),
+            "/src/teams/core/services/api/bin"
+        );
+        info.path = PathBuf::from("/src/*/a\\b/bin");
+        result.clear();
+        expand("%/{:\\1:/src/*/a\\b}", &mut info, &mut result).unwrap();
+        assert_eq!(str::from_utf8(&result).unwrap(), "\\1/bin");
+    }
+
     #[test]
     fn path_truncation() {
         let mut result = Vec::new();
@@ -869,6 +2806,7 @@ mod expand_tests {
             behind: 2,
             branch: "feature",
             stashes: 3,
+            named_dirs: Vec::new(),
         };
         expand("%/{:~:/home/user}", &mut info, &mut result).unwrap();
         assert_eq!(str::from_utf8(&result).unwrap(), "~");
@@ -884,6 +2822,7 @@ mod expand_tests {
             behind: 2,
             branch: "feature",
             stashes: 3,
+            named_dirs: Vec::new(),
         };
         expand("%/{:}", &mut info, &mut result).unwrap();
         assert_eq!(str::from_utf8(&result).unwrap(), "/");
@@ -2,26 +2,66 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs, clippy::pedantic)]
 
-use clap::Parser;
-use git2::{Branch, BranchType, Oid, Repository, StatusOptions};
-use shibuichi::{expand, util::ParsedScpUrl, Domain, Info};
+mod config;
+mod daemon;
+mod init;
+mod store;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use config::{BackendName, Config, FsmonitorName};
+use daemon::Daemon;
+use shibuichi::git::{Backend, Fsmonitor};
+use shibuichi::{expand_with_macros, util, Domain, GitInfo, Info, Needs, ParseError, Vcs};
+use std::collections::BTreeMap;
 use std::env;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use url::Url;
+use std::process;
+use std::time::{Duration, Instant};
+use store::Store;
 
 /// preprocess an expanded zsh prompt string
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Zsh style prompts to apply additional expansion to
     ///
     /// Multple prompts can be provided, if so each will be output delimited by `sep`. This can be
     /// used to store these values in `$psvar` to allow usage in the main zsh prompt without having
-    /// to change the prompt itself.
+    /// to change the prompt itself. A prompt that's exactly the name of a subcommand runs it
+    /// instead, so pass prompts after `--` if they could be.
     #[clap(value_parser)]
     prompts: Vec<String>,
 
+    /// Expand a named prompt from the config
+    ///
+    /// This can be repeated, and named prompts are output in order before any prompts passed
+    /// directly.
+    #[clap(long = "prompt", value_name = "NAME", global = true)]
+    named_prompts: Vec<String>,
+
+    /// Define a macro that can be used as `%{@NAME}`
+    ///
+    /// This can be repeated, and overrides macros with the same name in the config.
+    #[clap(
+        long = "macro",
+        value_name = "NAME=DEFINITION",
+        value_parser = parse_macro,
+        global = true
+    )]
+    macros: Vec<(String, String)>,
+
+    /// Path to the config
+    ///
+    /// Defaults to `$XDG_CONFIG_HOME/shibuichi/config.toml`, which is ignored if it doesn't exist.
+    /// Options passed on the command line override settings in the config.
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
     /// Separator for each "prompt"
     ///
     /// If specifying multiple "prompts" they will be separated by this character. The default,
@@ -35,257 +75,661 @@ struct Args {
     /// Use this flag to overwrite the separator with the null character
     #[clap(short = '0', long)]
     null: bool,
+
+    /// Zsh named directories to abbreviate paths with
+    ///
+    /// This should be the output of `hash -d`, e.g. `--named-dirs "$(hash -d)"`. Every path
+    /// expansion with prefix substitutions will replace the longest matching named directory with
+    /// `~name` before applying its own substitutions.
+    #[clap(long, default_value = "", global = true)]
+    named_dirs: String,
+
+    /// Socket of the status daemon
+    ///
+    /// If a daemon is listening on this socket, git status will be requested from it instead of
+    /// being computed. The socket must be in a directory only you can access. Defaults to
+    /// `shibuichi.sock` in `$XDG_RUNTIME_DIR`, or in a user specific directory in the temporary
+    /// directory.
+    #[clap(long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Use a status daemon if one is running, even if the config disables it
+    #[clap(long, global = true, overrides_with = "no_daemon")]
+    daemon: bool,
+
+    /// Don't use a status daemon even if one is running
+    #[clap(long, global = true, overrides_with = "daemon")]
+    no_daemon: bool,
+
+    /// Time budget for expensive git queries in milliseconds
+    ///
+    /// Git status and the commits ahead or behind the remote can be slow to compute in large
+    /// repositories. If they aren't finished within this many milliseconds of starting, they'll be
+    /// reported as unknown, which can be checked for with `%(k...)`.
+    #[clap(
+        long,
+        value_name = "MILLISECONDS",
+        global = true,
+        overrides_with = "no_timeout"
+    )]
+    timeout: Option<u64>,
+
+    /// Wait for expensive git queries however long they take, even if the config sets a timeout
+    #[clap(long, global = true, overrides_with = "timeout")]
+    no_timeout: bool,
+
+    /// How to read git repositories
+    ///
+    /// By default repositories are read with a library, and if it can't read a repository, e.g.
+    /// because it uses a newer extension, by running `git status`. `cli` always runs git. This is
+    /// also used by the daemon.
+    #[clap(long, value_name = "BACKEND", global = true)]
+    backend: Option<BackendName>,
+
+    /// Where to find out which files changed since status was last computed
+    ///
+    /// With libgit2, status only checks the files the monitor reports, along with the ones that
+    /// were modified last time, which is recorded in the cache. `hook` runs the hook configured
+    /// as `core.fsmonitor` in the repository, if there is one and the repository belongs to you,
+    /// and `watchman` asks watchman directly. Defaults to `none`.
+    #[clap(long, value_name = "MONITOR", global = true)]
+    fsmonitor: Option<FsmonitorName>,
+
+    /// Don't cache git information on disk
+    ///
+    /// By default the commits ahead or behind the remote, the number of stashes, and whether there
+    /// are staged files are cached in `$XDG_CACHE_HOME/shibuichi` until the repository changes.
+    #[clap(long, global = true, overrides_with = "cache")]
+    no_cache: bool,
+
+    /// Cache git information on disk, even if the config disables it
+    #[clap(long, global = true, overrides_with = "no_cache")]
+    cache: bool,
+
+    /// Output every prompt twice, first without slow git information
+    ///
+    /// The first time git status and the commits ahead or behind the remote are unknown, unless
+    /// they're cached, and can be checked for with `%(k...)`. Every prompt is terminated by `sep`
+    /// and the first set is flushed before the slow information is computed, so a shell can show
+    /// it right away and update it when the second set arrives.
+    #[clap(long = "async")]
+    two_phase: bool,
 }
 
-fn parse_git_origin(origin: &str) -> Option<String> {
-    // NOTE Url creates an owned copy instead of just referencing the string, so we can't just
-    // return a reference here
-    if let Some(domain) = Url::parse(origin).ok().as_ref().and_then(Url::domain) {
-        Some(domain.to_owned())
-    } else {
-        ParsedScpUrl::parse(origin).map(|url| url.host().to_owned())
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a daemon that keeps git status warm
+    ///
+    /// The daemon watches every repository it's asked about for changes so that status only needs
+    /// to be recomputed after something changed. Normal invocations will use a
+    /// running daemon automatically.
+    Daemon,
+
+    /// Check that prompts are well formed
+    ///
+    /// Malformed expansions are normally output as is, which makes them hard to spot. This reports
+    /// where each prompt is malformed, and exits with an error if any are. If no prompts are given
+    /// every prompt and macro in the config is checked.
+    Check {
+        /// The prompts to check
+        prompts: Vec<String>,
+    },
+
+    /// Show how prompts are parsed
+    ///
+    /// Prints the tree of each prompt, with shibuichi expansions annotated with what they mean and
+    /// what they currently expand to.
+    Explain {
+        /// The prompts to explain
+        prompts: Vec<String>,
+
+        /// Print each prompt as a line of JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print a script to integrate with a shell
+    ///
+    /// Load it in your shell config, e.g. `eval "$(shibuichi init zsh)"` in `.zshrc`.
+    Init {
+        /// The shell to integrate with
+        #[clap(value_enum)]
+        shell: Shell,
+
+        #[clap(flatten)]
+        options: init::Options,
+    },
 }
 
-#[derive(Default)]
-enum CachedRepo {
-    #[default]
-    Unknown,
-    NoRepo,
-    Repo(Repository),
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Shell {
+    Zsh,
 }
 
-impl CachedRepo {
-    fn get(&mut self) -> Option<&mut Repository> {
-        match self {
-            CachedRepo::NoRepo => None,
-            CachedRepo::Repo(repo) => Some(repo),
-            CachedRepo::Unknown => {
-                *self = match Repository::discover(".") {
-                    Ok(repo) => CachedRepo::Repo(repo),
-                    Err(_) => CachedRepo::NoRepo,
-                };
-                self.get()
-            }
+/// Show where a prompt is malformed, with a caret under the offending character
+fn diagnostic(prompt: &str, err: &ParseError) -> String {
+    let offset = err.offset();
+    let start = prompt[..offset].rfind('\n').map_or(0, |ind| ind + 1);
+    let end = prompt[offset..]
+        .find('\n')
+        .map_or(prompt.len(), |ind| offset + ind);
+    let column = prompt[start..offset].chars().count();
+    format!(
+        "{err}\n  {}\n  {}^",
+        &prompt[start..end],
+        " ".repeat(column)
+    )
+}
+
+/// Check every prompt, printing any problems, and returning true if they're all well formed
+fn check_prompts<'a>(
+    prompts: impl IntoIterator<Item = (String, &'a str)>,
+    macros: &BTreeMap<String, String>,
+) -> bool {
+    let mut valid = true;
+    for (name, prompt) in prompts {
+        let res = shibuichi::check(prompt)
+            .map_err(|err| diagnostic(prompt, &err))
+            .and_then(|()| {
+                shibuichi::needs_with_macros(prompt, macros).map_err(|err| err.to_string())
+            });
+        if let Err(msg) = res {
+            eprintln!("shibuichi: {name}{msg}");
+            valid = false;
         }
     }
+    valid
+}
 
-    fn branch(&mut self) -> Option<String> {
-        let repo = self.get()?;
-        let head = repo.head().ok()?;
-        let name = head.shorthand()?;
-        Some(name.to_owned())
-    }
-
-    fn get_ahead_behind(
-        repo: &Repository,
-        local: Oid,
-        upstream_branch: &Branch,
-    ) -> Option<(usize, usize)> {
-        let upstream = upstream_branch.get().target()?;
-        repo.graph_ahead_behind(local, upstream).ok()
-    }
-
-    fn get_domain(repo: &Repository, upstream_branch: &Branch) -> Option<Domain> {
-        let upstream_name = upstream_branch.name().ok()??;
-        let remote_name = upstream_name.split('/').next()?;
-        let remote = repo.find_remote(remote_name).ok()?;
-        let url = parse_git_origin(remote.url()?)?;
-        let domain = match url.as_ref() {
-            "github.com" => Domain::Github,
-            "gitlab.com" => Domain::Gitlab,
-            "bitbucket.org" => Domain::BitBucket,
-            "dev.azure.com" => Domain::Azure,
-            _ => Domain::Git,
-        };
-        Some(domain)
-    }
-
-    fn remote_info(&mut self) -> Option<(Domain, usize, usize)> {
-        let repo = self.get()?;
-        let head = repo.head().ok()?;
-        let branch = head.shorthand()?;
-        let local = head.target()?;
-        let local_branch = repo.find_branch(branch, BranchType::Local).ok()?;
-        let upstream_branch = local_branch.upstream().ok()?;
-        let (num_ahead, num_behind) =
-            CachedRepo::get_ahead_behind(repo, local, &upstream_branch).unwrap_or_default();
-        let domain = CachedRepo::get_domain(repo, &upstream_branch).unwrap_or(Domain::Git);
-        Some((domain, num_ahead, num_behind))
-    }
-
-    fn stashes(&mut self) -> usize {
-        let mut stashes = 0;
-        if let Some(repo) = self.get() {
-            repo.stash_foreach(|_, _, _| {
-                stashes += 1;
-                true
-            })
-            .unwrap();
-        }
-        stashes
-    }
-
-    fn status(&mut self) -> Option<(bool, bool, bool)> {
-        let repo = self.get()?;
-        let mut dirty = false;
-        let mut modified = false;
-        let mut staged = false;
-
-        if let Ok(statuses) = repo.statuses(Some(StatusOptions::new().include_untracked(true))) {
-            for status in statuses.iter() {
-                dirty = true;
-
-                let status = status.status();
-                if !modified && status.is_wt_new()
-                    || status.is_wt_modified()
-                    || status.is_wt_renamed()
-                    || status.is_wt_typechange()
-                {
-                    modified = true;
-                }
-                if !staged && status.is_index_new()
-                    || status.is_index_modified()
-                    || status.is_index_deleted()
-                    || status.is_index_renamed()
-                    || status.is_index_typechange()
-                {
-                    staged = true;
-                }
-
-                if modified && staged {
-                    break;
-                }
-            }
+/// Check every prompt and macro in the config
+fn check_config(config: &Config, macros: &BTreeMap<String, String>) -> bool {
+    let prompts = config
+        .prompts
+        .iter()
+        .map(|(name, prompt)| (format!("prompt \"{name}\": "), prompt.as_str()));
+    let macro_defs = macros
+        .iter()
+        .map(|(name, def)| (format!("macro \"{name}\": "), def.as_str()));
+    let backend = match config.backend.map(BackendName::backend) {
+        Some(Err(err)) => {
+            eprintln!("shibuichi: {err}");
+            false
         }
-        Some((dirty, modified, staged))
+        _ => true,
+    };
+    check_prompts(prompts.chain(macro_defs), macros) && backend
+}
+
+fn parse_macro(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((name, def)) => Ok((name.to_owned(), def.to_owned())),
+        None => Err(format!("expected NAME=DEFINITION, got \"{raw}\"")),
     }
 }
 
-#[derive(Default)]
+/// Git information backed by the disk cache
 struct Cache {
-    path: Option<PathBuf>,
-    repo: CachedRepo,
-    remote_info: Option<(Domain, usize, usize)>,
-    branch: Option<String>,
-    stashes: Option<usize>,
-    status: Option<(bool, bool, bool)>,
+    info: GitInfo,
+    store_dir: Option<PathBuf>,
+    store: Option<Store>,
+    settings: String,
 }
 
 impl Cache {
-    fn git_remote_info(&mut self) -> &(Domain, usize, usize) {
-        match &mut self.remote_info {
-            Some(info) => info,
-            info @ None => {
-                *info = Some(self.repo.remote_info().unwrap_or((Domain::Git, 0, 0)));
-                info.as_ref().unwrap()
-            }
+    fn store(&mut self) -> Option<&mut Store> {
+        if let Some(dir) = self.store_dir.take() {
+            let settings = &self.settings;
+            self.store = self
+                .info
+                .repository()
+                .map(|repo| Store::open(&dir, repo, settings));
         }
+        self.store.as_mut()
     }
 
-    fn git_status(&mut self) -> &(bool, bool, bool) {
-        match &mut self.status {
-            Some(status) => status,
-            status @ None => {
-                *status = Some(self.repo.status().unwrap_or_default());
-                status.as_ref().unwrap()
+    /// Use cached remote information instead of computing it
+    fn seed_remote_info(&mut self) {
+        if let Some(info) = self.store().and_then(|store| store.values.remote_info) {
+            self.info.set_remote_info(info);
+        }
+    }
+
+    /// Update status from the cached snapshot instead of computing it fully
+    fn seed_snapshot(&mut self) {
+        if !self.info.status_started() {
+            if let Some(snapshot) = self.store().and_then(|store| store.values.snapshot.clone()) {
+                self.info.set_snapshot(snapshot);
             }
         }
     }
+
+    fn save(&mut self) {
+        let remote_info = self.info.computed_remote_info();
+        let staged = self.info.computed_status().map(|status| status.2);
+        let snapshot = self.info.computed_snapshot();
+        let stashes = self.info.computed_stashes();
+        if remote_info.is_none() && staged.is_none() && stashes.is_none() {
+            return;
+        }
+        if let Some(store) = self.store() {
+            let values = &mut store.values;
+            values.remote_info = remote_info.or(values.remote_info);
+            values.staged = staged.or(values.staged);
+            values.snapshot = snapshot.or(values.snapshot.take());
+            values.stashes = stashes.or(values.stashes);
+            // NOTE failing to cache shouldn't fail the prompt
+            let _ = store.save();
+        }
+    }
 }
 
 impl Info for Cache {
     fn current_path(&mut self) -> &Path {
-        match &mut self.path {
-            Some(buf) => buf,
-            buf @ None => {
-                let path = if let Ok(pwd) = env::var("PWD") {
-                    PathBuf::from(pwd)
-                } else if let Ok(cwd) = env::current_dir() {
-                    cwd
-                } else {
-                    PathBuf::new()
-                };
-                *buf = Some(path);
-                buf.as_ref().unwrap()
-            }
-        }
-        .as_path()
+        self.info.current_path()
     }
 
     fn git_exists(&mut self) -> bool {
-        self.repo.get().is_some()
+        self.info.git_exists()
     }
 
     fn git_dirty(&mut self) -> bool {
-        self.git_status().0
+        self.seed_snapshot();
+        self.info.git_dirty()
     }
 
     fn git_modified(&mut self) -> bool {
-        self.git_status().1
+        self.seed_snapshot();
+        self.info.git_modified()
     }
 
     fn git_staged(&mut self) -> bool {
-        self.git_status().2
+        // NOTE the cache is only loaded for the same HEAD and index, so this holds even if status
+        // was started
+        if let Some(staged) = self.store().and_then(|store| store.values.staged) {
+            return staged;
+        }
+        self.seed_snapshot();
+        self.info.git_staged()
+    }
+
+    fn git_status_known(&mut self) -> bool {
+        self.seed_snapshot();
+        self.info.git_status_known()
     }
 
     fn git_remote_domain(&mut self) -> Domain {
-        self.git_remote_info().0
+        self.seed_remote_info();
+        self.info.git_remote_domain()
     }
 
     fn git_remote_ahead(&mut self) -> usize {
-        self.git_remote_info().1
+        self.seed_remote_info();
+        self.info.git_remote_ahead()
     }
 
     fn git_remote_behind(&mut self) -> usize {
-        self.git_remote_info().2
+        self.seed_remote_info();
+        self.info.git_remote_behind()
+    }
+
+    fn git_remote_known(&mut self) -> bool {
+        self.seed_remote_info();
+        self.info.git_remote_known()
     }
 
     fn git_branch(&mut self) -> &str {
-        match &mut self.branch {
-            Some(branch) => branch,
-            branch @ None => {
-                *branch = Some(self.repo.branch().unwrap_or_default());
-                branch.as_ref().unwrap()
-            }
-        }
+        self.info.git_branch()
+    }
+
+    fn git_root(&mut self) -> Option<&Path> {
+        self.info.git_root()
     }
 
     fn git_stashes(&mut self) -> usize {
-        match &mut self.stashes {
-            Some(stashes) => *stashes,
-            stashes @ None => {
-                let res = self.repo.stashes();
-                *stashes = Some(res);
-                res
+        if self.info.computed_stashes().is_none() {
+            if let Some(stashes) = self.store().and_then(|store| store.values.stashes) {
+                self.info.set_stashes(stashes);
             }
         }
+        self.info.git_stashes()
+    }
+
+    fn vcs(&mut self) -> Vcs {
+        self.info.vcs()
+    }
+
+    fn jj_exists(&mut self) -> bool {
+        self.info.jj_exists()
+    }
+
+    fn jj_change_id(&mut self) -> &str {
+        self.info.jj_change_id()
+    }
+
+    fn jj_empty(&mut self) -> bool {
+        self.info.jj_empty()
+    }
+
+    fn jj_conflict(&mut self) -> bool {
+        self.info.jj_conflict()
+    }
+
+    fn named_dirs(&mut self) -> &[(String, PathBuf)] {
+        self.info.named_dirs()
+    }
+
+    fn prefetch(&mut self, needs: &Needs) {
+        // NOTE computing status can refresh the index, so the store is opened first
+        if needs.status {
+            self.seed_snapshot();
+        }
+        if needs.remote {
+            self.seed_remote_info();
+        }
+        self.info.prefetch(needs);
     }
 }
 
-fn main() {
-    let args = Args::parse();
-    let mut cache = Cache::default();
+/// Load the config, exiting if it's broken and `strict`
+///
+/// Otherwise a broken config is reported and the defaults are used, so that it doesn't take every
+/// prompt down with it.
+fn load_config(path: Option<&Path>, strict: bool) -> Config {
+    Config::load(path).unwrap_or_else(|err| {
+        eprintln!("shibuichi: {err}");
+        if strict {
+            process::exit(1);
+        }
+        Config::default()
+    })
+}
+
+/// Get the backend to read repositories with, preferring the one passed on the command line
+///
+/// A backend that isn't supported by this build is reported and skipped, so that a config shared
+/// between builds doesn't take every prompt down with it.
+fn backend(names: [Option<BackendName>; 2]) -> Backend {
+    names
+        .into_iter()
+        .flatten()
+        .find_map(|name| {
+            name.backend()
+                .map_err(|err| eprintln!("shibuichi: {err}, ignoring it"))
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Get whether a setting is on, where `--flag` and `--no-flag` take precedence over the config
+fn flag(on: bool, off: bool, configured: Option<bool>) -> bool {
+    (on || off).then_some(on).or(configured).unwrap_or(true)
+}
+
+/// Get the file system monitor to limit status with, which defaults to none
+fn fsmonitor(name: Option<FsmonitorName>) -> Option<Fsmonitor> {
+    name.and_then(FsmonitorName::fsmonitor)
+}
+
+/// Expand each prompt followed by a separator
+///
+/// With `two_phase`, every prompt is first expanded with only the information that's already
+/// known, and then again once everything is computed.
+fn expand_prompts(
+    prompts: &[String],
+    macros: &BTreeMap<String, String>,
+    cache: &mut Cache,
+    sep: char,
+    two_phase: bool,
+) {
     let mut out = io::stdout().lock();
+    if two_phase {
+        cache.info.set_fast(true);
+        for prompt in prompts {
+            expand_with_macros(prompt, macros, cache, &mut out).unwrap();
+            write!(out, "{sep}").unwrap();
+        }
+        out.flush().unwrap();
+        cache.info.set_fast(false);
+    }
     let mut not_first = false;
-    let sep = if args.null { '\0' } else { args.sep };
-    for prompt in args.prompts {
+    for prompt in prompts {
         if not_first {
             write!(out, "{sep}").unwrap();
         } else {
             not_first = true;
         }
 
-        expand(prompt, &mut cache, &mut out).unwrap();
+        expand_with_macros(prompt, macros, cache, &mut out).unwrap();
+    }
+    if two_phase && not_first {
+        write!(out, "{sep}").unwrap();
+    }
+}
+
+/// Print the parsed tree of each prompt
+fn explain_prompts(
+    prompts: &[String],
+    macros: &BTreeMap<String, String>,
+    cache: &mut Cache,
+    json: bool,
+) {
+    let mut out = io::stdout().lock();
+    for (ind, prompt) in prompts.iter().enumerate() {
+        let nodes = shibuichi::explain(prompt, macros, cache).unwrap_or_else(|err| {
+            eprintln!("shibuichi: {err}");
+            process::exit(1);
+        });
+        if json {
+            serde_json::to_writer(&mut out, &nodes).unwrap();
+            writeln!(out).unwrap();
+        } else {
+            if ind > 0 {
+                writeln!(out).unwrap();
+            }
+            shibuichi::ast::write_tree(&nodes, &mut out).unwrap();
+        }
+    }
+}
+
+fn main() {
+    let start = Instant::now();
+    let args = Args::parse();
+    let socket = args.socket.unwrap_or_else(daemon::default_socket);
+    match &args.command {
+        Some(Command::Daemon) => {
+            let listener = Daemon::bind(&socket).unwrap_or_else(|err| {
+                eprintln!("shibuichi: {err}");
+                process::exit(1);
+            });
+            let config = load_config(args.config.as_deref(), false);
+            Daemon::new(backend([args.backend, config.backend]))
+                .serve(&listener)
+                .unwrap();
+            return;
+        }
+        Some(Command::Init {
+            shell: Shell::Zsh,
+            options,
+        }) => {
+            print!("{}", init::zsh(options));
+            return;
+        }
+        Some(Command::Check { .. } | Command::Explain { .. }) | None => (),
+    }
+
+    let config = load_config(
+        args.config.as_deref(),
+        matches!(args.command, Some(Command::Check { .. })),
+    );
+    let mut prompts = args
+        .named_prompts
+        .iter()
+        .map(|name| config.prompt(name).map(str::to_owned))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|err| {
+            eprintln!("shibuichi: {err}");
+            process::exit(1);
+        });
+    prompts.extend(args.prompts);
+    let mut macros = config.macros.clone();
+    macros.extend(args.macros);
+
+    if let Some(Command::Check { prompts: checked }) = &args.command {
+        let valid = if checked.is_empty() && prompts.is_empty() {
+            check_config(&config, &macros)
+        } else {
+            let all = prompts.iter().chain(checked);
+            check_prompts(all.map(|prompt| (String::new(), prompt.as_str())), &macros)
+        };
+        process::exit(i32::from(!valid));
+    }
+    if let Some(Command::Explain {
+        prompts: explained, ..
+    }) = &args.command
+    {
+        prompts.extend(explained.iter().cloned());
+    }
+
+    let use_daemon = flag(args.daemon, args.no_daemon, config.daemon);
+    let use_cache = flag(args.cache, args.no_cache, config.cache);
+    let timeout = args.timeout.or(config.timeout).filter(|_| !args.no_timeout);
+    let path = env::var_os("PWD")
+        .map(PathBuf::from)
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_default();
+    // NOTE cached domains depend on the configured mappings
+    let settings = format!("{:?}", config.domains);
+    let mut info = GitInfo::new(path)
+        .with_backend(backend([args.backend, config.backend]))
+        .with_named_dirs(util::parse_named_dirs(&args.named_dirs))
+        .with_deadline(timeout.map(|timeout| start + Duration::from_millis(timeout)))
+        .with_domains(config.domains);
+    // NOTE without the cache there's never a snapshot to update
+    if let Some(fsmonitor) = fsmonitor(args.fsmonitor.or(config.fsmonitor)).filter(|_| use_cache) {
+        info = info.with_fsmonitor(fsmonitor);
+    }
+    if use_daemon {
+        info = info.with_status_source(move |root| daemon::query(&socket, root));
     }
+    let mut cache = Cache {
+        info,
+        store_dir: use_cache.then(store::default_dir).flatten(),
+        store: None,
+        settings,
+    };
+    // NOTE everything the prompts need is started together, so it can be computed concurrently
+    let needs = prompts
+        .iter()
+        .map(|prompt| shibuichi::needs_with_macros(prompt, &macros))
+        .try_fold(Needs::default(), |acc, needs| {
+            Ok::<_, shibuichi::Error>(acc | needs?)
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("shibuichi: {err}");
+            process::exit(1);
+        });
+    cache.prefetch(&needs);
+    if let Some(Command::Explain { json, .. }) = args.command {
+        explain_prompts(&prompts, &macros, &mut cache, json);
+        cache.save();
+        return;
+    }
+    let sep = if args.null { '\0' } else { args.sep };
+    expand_prompts(&prompts, &macros, &mut cache, sep, args.two_phase);
+    cache.save();
 }
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_diagnostic() {
+        let err = shibuichi::check("a %(y.b.c").unwrap_err();
+        assert_eq!(
+            super::diagnostic("a %(y.b.c", &err),
+            "expected `)` at byte 9\n  a %(y.b.c\n           ^"
+        );
+        let prompt = "first\nä %Zx\nlast";
+        let err = shibuichi::check(prompt).unwrap_err();
+        assert_eq!(
+            super::diagnostic(prompt, &err),
+            "expected an escape at byte 10\n  ä %Zx\n     ^"
+        );
+    }
 
     #[test]
-    fn test_parse_git_origin() {
-        let domain = super::parse_git_origin("git@github.com:path/file.git").unwrap();
-        assert_eq!(domain, "github.com");
+    fn cached_staged() {
+        use super::store::Store;
+        use super::Cache;
+        use shibuichi::git::{Backend, GitInfo, Repo};
+        use shibuichi::{Info, Needs};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        git2::Repository::init(&root).unwrap();
+        let store_dir = dir.path().join("cache");
+        let repo = Repo::open(&root, Backend::default()).unwrap();
+        let mut store = Store::open(&store_dir, &repo, "");
+        // NOTE nothing is staged, so this can only come from the cache
+        store.values.staged = Some(true);
+        store.save().unwrap();
+
+        let mut cache = Cache {
+            info: GitInfo::new(&root),
+            store_dir: Some(store_dir),
+            store: None,
+            settings: String::new(),
+        };
+        cache.prefetch(&Needs {
+            status: true,
+            ..Needs::default()
+        });
+        assert!(cache.git_staged());
+    }
+
+    #[test]
+    fn prompts_named_like_subcommands() {
+        use super::{Args, Command};
+        use clap::Parser;
+
+        let args = Args::try_parse_from(["shibuichi", "check"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Check { .. })));
+        let args = Args::try_parse_from(["shibuichi", "--", "check", "daemon"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.prompts, ["check", "daemon"]);
+    }
+
+    #[test]
+    fn flags_override_config() {
+        use super::{flag, Args};
+        use clap::Parser;
+
+        let args = Args::try_parse_from(["shibuichi", "--daemon", "--no-cache"]).unwrap();
+        assert!(flag(args.daemon, args.no_daemon, Some(false)));
+        assert!(!flag(args.cache, args.no_cache, Some(true)));
+        let args = Args::try_parse_from(["shibuichi", "--no-daemon", "--daemon"]).unwrap();
+        assert!(flag(args.daemon, args.no_daemon, Some(false)));
+        let args = Args::try_parse_from(["shibuichi"]).unwrap();
+        assert!(!flag(args.daemon, args.no_daemon, Some(false)));
+        assert!(flag(args.cache, args.no_cache, None));
+
+        let args = Args::try_parse_from(["shibuichi", "--timeout", "5", "--no-timeout"]).unwrap();
+        assert_eq!((args.timeout, args.no_timeout), (None, true));
+        let args = Args::try_parse_from(["shibuichi", "--no-timeout", "--timeout", "5"]).unwrap();
+        assert_eq!((args.timeout, args.no_timeout), (Some(5), false));
+    }
+    #[test]
+    fn unsupported_backend() {
+        use super::{backend, BackendName};
+        use shibuichi::git::Backend;
+
+        assert_eq!(backend([None, None]), Backend::default());
+        assert_eq!(backend([None, Some(BackendName::Cli)]), Backend::Cli);
+        #[cfg(not(feature = "gix"))]
+        assert_eq!(
+            backend([Some(BackendName::Gix), Some(BackendName::Cli)]),
+            Backend::Cli
+        );
+        #[cfg(not(feature = "git2"))]
+        assert_eq!(backend([None, Some(BackendName::Git2)]), Backend::default());
     }
 }
@@ -0,0 +1,412 @@
+//! A persistent on-disk cache of expensive git information
+//!
+//! Every repository gets a small file in the cache directory with the information computed for
+//! it, and a key describing the state of the repository when it was computed. The key includes
+//! the HEAD and upstream commits, and stamps of the index, config, and stash files, so that any
+//! change that could affect a cached value invalidates the whole file.
+//!
+//! Status of the working tree isn't part of the key, so whether there are modified or untracked
+//! files is never cached. Staged files only depend on HEAD and the index, so they are. The file
+//! system monitor snapshot is, since it only says which files status needs to check again.
+//!
+//! Files are named by a hash of the repository's git directory, and saving removes files for
+//! repositories that no longer exist, or that haven't been used in a while.
+use shibuichi::git::{Repo, Snapshot};
+use shibuichi::Domain;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+/// How long a cache file can go unused before it's removed
+const STALE_AFTER: Duration = Duration::from_hours(24 * 30);
+
+/// The default cache directory
+pub fn default_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(cache) if !cache.is_empty() => PathBuf::from(cache),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("shibuichi"))
+}
+
+/// Information that can be cached
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Values {
+    pub remote_info: Option<(Domain, usize, usize)>,
+    pub stashes: Option<usize>,
+    pub staged: Option<bool>,
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Values {
+    fn parse(lines: &str) -> Option<Self> {
+        let mut values = Values::default();
+        for line in lines.lines() {
+            // NOTE tokens and paths can contain spaces, so they're the rest of the line
+            match line.split_once(' ') {
+                Some(("fsmonitor", token)) => {
+                    values.snapshot = Some(Snapshot {
+                        token: token.to_owned(),
+                        modified: Vec::new(),
+                    });
+                    continue;
+                }
+                Some(("modified", path)) => {
+                    values.snapshot.as_mut()?.modified.push(path.to_owned());
+                    continue;
+                }
+                _ => (),
+            }
+            let mut words = line.split(' ');
+            match (words.next()?, words.next(), words.next(), words.next()) {
+                ("remote", Some(domain), Some(ahead), Some(behind)) => {
+                    let domain = match domain {
+                        "0" => Domain::Git,
+                        "1" => Domain::Github,
+                        "2" => Domain::Gitlab,
+                        "3" => Domain::BitBucket,
+                        "4" => Domain::Azure,
+                        _ => return None,
+                    };
+                    values.remote_info = Some((domain, ahead.parse().ok()?, behind.parse().ok()?));
+                }
+                ("stashes", Some(stashes), None, None) => {
+                    values.stashes = Some(stashes.parse().ok()?);
+                }
+                ("staged", Some(staged), None, None) => {
+                    values.staged = Some(staged == "1");
+                }
+                _ => return None,
+            }
+        }
+        Some(values)
+    }
+
+    fn write(&self, out: &mut String) {
+        if let Some((domain, ahead, behind)) = self.remote_info {
+            writeln!(out, "remote {} {ahead} {behind}", domain as u8).unwrap();
+        }
+        if let Some(stashes) = self.stashes {
+            writeln!(out, "stashes {stashes}").unwrap();
+        }
+        if let Some(staged) = self.staged {
+            writeln!(out, "staged {}", u8::from(staged)).unwrap();
+        }
+        if let Some(snapshot) = &self.snapshot {
+            // NOTE a snapshot that can't be written is just a full status next time
+            let mut lines = iter::once(&snapshot.token).chain(&snapshot.modified);
+            if !lines.any(|line| line.contains('\n')) {
+                writeln!(out, "fsmonitor {}", snapshot.token).unwrap();
+                for path in &snapshot.modified {
+                    writeln!(out, "modified {path}").unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Describe the state of a file, or note that it's missing
+fn stamp(out: &mut String, name: &str, path: &Path) {
+    match fs::metadata(path) {
+        Ok(meta) => writeln!(
+            out,
+            "{name} {} {}.{} {}",
+            meta.ino(),
+            meta.mtime(),
+            meta.mtime_nsec(),
+            meta.size()
+        ),
+        Err(_) => writeln!(out, "{name} -"),
+    }
+    .unwrap();
+}
+
+/// Hash a path with 64 bit FNV-1a, which unlike the standard library's hasher never changes
+fn hash_path(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Whether the cache file at `path` can be removed
+fn is_stale(path: &Path, now: SystemTime) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+    let unused = meta
+        .modified()
+        .ok()
+        .and_then(|modified| now.duration_since(modified).ok());
+    if unused.is_some_and(|unused| unused > STALE_AFTER) {
+        return true;
+    }
+    // NOTE the key starts with the git directory, which is gone if the repository was removed
+    fs::read_to_string(path).is_ok_and(|contents| {
+        contents
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("repo "))
+            .is_some_and(|git_dir| !Path::new(git_dir).exists())
+    })
+}
+
+/// Remove stale cache files in `dir`, other than `keep`
+fn prune(dir: &Path, keep: &Path) -> io::Result<()> {
+    let now = SystemTime::now();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path != keep && is_stale(&path, now) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compute the key for the current state of `repo`
+fn repo_key(repo: &Repo, settings: &str) -> String {
+    let git_dir = repo.git_dir();
+    // NOTE linked worktrees have their own HEAD and index, but share everything else
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim_end()),
+        Err(_) => git_dir.to_owned(),
+    };
+
+    let mut key = String::new();
+    writeln!(key, "repo {}", git_dir.display()).unwrap();
+    writeln!(key, "settings {settings}").unwrap();
+    let oid = |id: Option<String>| id.unwrap_or_else(|| "-".to_owned());
+    writeln!(key, "head {}", oid(repo.head_id())).unwrap();
+    writeln!(key, "upstream {}", oid(repo.upstream_id())).unwrap();
+    stamp(&mut key, "index", &git_dir.join("index"));
+    stamp(&mut key, "config", &common_dir.join("config"));
+    stamp(&mut key, "stash", &common_dir.join("refs").join("stash"));
+    stamp(
+        &mut key,
+        "stash-log",
+        &common_dir.join("logs").join("refs").join("stash"),
+    );
+    key
+}
+
+/// The cached information for a single repository
+pub struct Store {
+    file: PathBuf,
+    key: String,
+    loaded: Values,
+    /// The current values, which will be written on save if they changed
+    pub values: Values,
+}
+
+impl Store {
+    /// Open the cache for `repo` in `dir`
+    ///
+    /// Values are only loaded if they were cached for the current state of the repository, and the
+    /// same `settings`, which should describe any configuration that affects the values.
+    pub fn open(dir: &Path, repo: &Repo, settings: &str) -> Self {
+        let file = dir.join(format!("{:016x}", hash_path(repo.git_dir())));
+        let key = repo_key(repo, settings);
+        let loaded = fs::read_to_string(&file)
+            .ok()
+            .and_then(|contents| {
+                let (stored, values) = contents.split_once("\n\n")?;
+                (stored == key.trim_end()).then(|| Values::parse(values))?
+            })
+            .unwrap_or_default();
+        Store {
+            file,
+            key,
+            values: loaded.clone(),
+            loaded,
+        }
+    }
+
+    /// Write the current values if they changed since they were loaded, and prune stale files
+    ///
+    /// # Errors
+    ///
+    /// If the cache directory or file can't be written.
+    pub fn save(&self) -> io::Result<()> {
+        if self.values == self.loaded {
+            return Ok(());
+        }
+        let mut contents = self.key.clone();
+        contents.push('\n');
+        self.values.write(&mut contents);
+        let dir = self.file.parent().unwrap();
+        fs::create_dir_all(dir)?;
+        // NOTE write then rename so concurrent prompts never see a partial file
+        let temp = self.file.with_extension(process::id().to_string());
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, &self.file)?;
+        prune(dir, &self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_path, Store, Values, STALE_AFTER};
+    use git2::{Oid, Repository, Signature};
+    use shibuichi::git::{Backend, Repo, Snapshot};
+    use shibuichi::Domain;
+    use std::fs;
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    fn commit(repo: &Repository, name: &str) -> Oid {
+        let root = repo.workdir().unwrap();
+        fs::write(root.join(name), name).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, name, &tree, &parents)
+            .unwrap()
+    }
+
+    fn values() -> Values {
+        Values {
+            remote_info: Some((Domain::Gitlab, 2, 1)),
+            stashes: Some(3),
+            staged: Some(true),
+            snapshot: Some(Snapshot {
+                token: "c:1 2".to_owned(),
+                modified: vec!["first".to_owned(), "with space".to_owned()],
+            }),
+        }
+    }
+
+    /// Save values for `repo` and then return if they're still loaded after `change`
+    fn survives(dir: &Path, repo: &mut Repository, change: impl FnOnce(&mut Repository)) -> bool {
+        // NOTE the repository is reopened every time, so that it sees changes
+        let open = |repo: &Repository, settings| {
+            let repo = Repo::open(repo.path(), Backend::default()).unwrap();
+            Store::open(dir, &repo, settings)
+        };
+        let mut store = open(repo, "");
+        store.values = values();
+        store.save().unwrap();
+        assert_eq!(open(repo, "").values, values());
+        assert_eq!(open(repo, "other").values, Values::default());
+        change(repo);
+        let loaded = open(repo, "").values;
+        assert!(loaded == values() || loaded == Values::default());
+        loaded == values()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut out = String::new();
+        values().write(&mut out);
+        assert_eq!(Values::parse(&out), Some(values()));
+        assert_eq!(Values::parse(""), Some(Values::default()));
+        assert_eq!(Values::parse("remote 7 0 0\n"), None);
+        assert_eq!(Values::parse("unknown 1\n"), None);
+        assert_eq!(Values::parse("modified first\n"), None);
+
+        let mut values = values();
+        values
+            .snapshot
+            .as_mut()
+            .unwrap()
+            .modified
+            .push("new\nline".to_owned());
+        out.clear();
+        values.write(&mut out);
+        values.snapshot = None;
+        assert_eq!(Values::parse(&out), Some(values));
+    }
+
+    #[test]
+    fn invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let mut repo = Repository::init(dir.path().join("repo")).unwrap();
+        commit(&repo, "first");
+
+        assert!(survives(&cache, &mut repo, |_| ()));
+        assert!(survives(&cache, &mut repo, |repo| {
+            fs::write(repo.workdir().unwrap().join("first"), "modified").unwrap();
+        }));
+        assert!(!survives(&cache, &mut repo, |repo| {
+            commit(repo, "second");
+        }));
+        assert!(!survives(&cache, &mut repo, |repo| {
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("first")).unwrap();
+            index.write().unwrap();
+        }));
+        assert!(!survives(&cache, &mut repo, |repo| {
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.stash_save(&sig, "stash", None).unwrap();
+        }));
+        assert!(!survives(&cache, &mut repo, |repo| {
+            repo.remote("origin", "https://gitlab.com/user/repo.git")
+                .unwrap();
+        }));
+
+        let head = repo.head().unwrap().target().unwrap();
+        let first = repo.find_commit(head).unwrap().parent_id(0).unwrap();
+        repo.reference("refs/remotes/origin/main", first, true, "")
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{branch}.remote"), "origin")
+            .unwrap();
+        config
+            .set_str(&format!("branch.{branch}.merge"), "refs/heads/main")
+            .unwrap();
+        assert!(!survives(&cache, &mut repo, |repo| {
+            repo.reference("refs/remotes/origin/main", head, true, "")
+                .unwrap();
+        }));
+    }
+
+    #[test]
+    fn pruning() {
+        // NOTE file names have to stay the same across builds to be found again
+        assert_eq!(hash_path(Path::new("")), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash_path(Path::new("/repo/.git")), 0x892b_7217_9649_07c9);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let repo = Repository::init(dir.path().join("repo")).unwrap();
+        let removed = Repository::init(dir.path().join("removed")).unwrap();
+        let unused = Repository::init(dir.path().join("unused")).unwrap();
+        let save = |repo: &Repository| {
+            let repo = Repo::open(repo.path(), Backend::default()).unwrap();
+            let mut store = Store::open(&cache, &repo, "");
+            store.values = values();
+            store.save().unwrap();
+            store.file
+        };
+        let removed_file = save(&removed);
+        let unused_file = save(&unused);
+        fs::remove_dir_all(removed.path()).unwrap();
+        let old = SystemTime::now() - STALE_AFTER * 2;
+        fs::File::options()
+            .write(true)
+            .open(&unused_file)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let file = save(&repo);
+        assert!(file.exists());
+        assert!(!removed_file.exists());
+        assert!(!unused_file.exists());
+    }
+}
@@ -0,0 +1,232 @@
+//! Subversion information
+//!
+//! Working copies keep everything in the sqlite database `.svn/wc.db`, which is read directly
+//! instead of running `svn`. The branch is the repository path the root of the working copy is
+//! checked out from, e.g. `trunk` or `branches/feature`. Local additions, deletions, property
+//! changes and conflicts are recorded in the database, and modified files are found by comparing
+//! the recorded size and modification time, and then contents with the pristine copy, like `svn`
+//! does. Untracked files aren't looked for.
+use rusqlite::{Connection, OpenFlags};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long to wait for svn to release the database
+const BUSY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A subversion working copy
+#[derive(Debug, Clone)]
+pub struct Repo {
+    root: PathBuf,
+}
+
+impl Repo {
+    /// Find the working copy containing `dir`
+    ///
+    /// Only working copies from subversion 1.7 or later, which have a single database at the
+    /// root, are found.
+    #[must_use]
+    pub fn discover(dir: &Path) -> Option<Self> {
+        let root = dir
+            .ancestors()
+            .find(|dir| dir.join(".svn").join("wc.db").is_file())?;
+        Some(Repo {
+            root: root.to_owned(),
+        })
+    }
+
+    /// The root of the working copy
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn open(&self) -> Option<Connection> {
+        let path = self.root.join(".svn").join("wc.db");
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+        db.busy_timeout(BUSY_TIMEOUT).ok()?;
+        Some(db)
+    }
+
+    /// The repository path of the working copy, e.g. `trunk`
+    #[must_use]
+    pub fn branch(&self) -> Option<String> {
+        self.open()?
+            .query_row(
+                "SELECT repos_path FROM nodes WHERE local_relpath = '' AND op_depth = 0",
+                [],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    /// Whether the working copy is dirty, has modified files, and has staged files
+    ///
+    /// Subversion has no staging area, so every change counts as modified. This is clean if the
+    /// database can't be read.
+    #[must_use]
+    pub fn status(&self) -> (bool, bool, bool) {
+        let dirty = self
+            .open()
+            .is_some_and(|db| self.changed(&db).unwrap_or(false));
+        (dirty, dirty, false)
+    }
+
+    fn changed(&self, db: &Connection) -> rusqlite::Result<bool> {
+        // NOTE working nodes above the base layer are local additions, deletions and copies
+        let recorded = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM nodes WHERE op_depth > 0)
+                OR EXISTS (SELECT 1 FROM actual_node
+                    WHERE properties IS NOT NULL OR conflict_data IS NOT NULL)",
+            [],
+            |row| row.get(0),
+        )?;
+        if recorded {
+            return Ok(true);
+        }
+        let mut files = db.prepare(
+            "SELECT local_relpath, checksum, translated_size, last_mod_time FROM nodes
+                WHERE op_depth = 0 AND kind = 'file' AND presence = 'normal'",
+        )?;
+        let mut rows = files.query([])?;
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let checksum: Option<String> = row.get(1)?;
+            let size: Option<i64> = row.get(2)?;
+            let mtime: Option<i64> = row.get(3)?;
+            if self.file_changed(&path, checksum.as_deref(), size, mtime) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn file_changed(
+        &self,
+        path: &str,
+        checksum: Option<&str>,
+        size: Option<i64>,
+        mtime: Option<i64>,
+    ) -> bool {
+        let path = self.root.join(path);
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            return true;
+        };
+        if size.is_some_and(|size| u64::try_from(size).ok() != Some(meta.len())) {
+            return true;
+        }
+        // NOTE modification times are recorded in microseconds
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .and_then(|time| i64::try_from(time.as_micros()).ok());
+        if modified.is_some() && modified == mtime {
+            return false;
+        }
+        let pristine = checksum
+            .and_then(|checksum| checksum.strip_prefix("$sha1$"))
+            .and_then(|sha| {
+                let dir = self.root.join(".svn").join("pristine").join(sha.get(..2)?);
+                fs::read(dir.join(format!("{sha}.svn-base"))).ok()
+            });
+        match (pristine, fs::read(&path)) {
+            (Some(pristine), Ok(contents)) => pristine != contents,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repo;
+    use rusqlite::{params, Connection};
+    use std::fs;
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+
+    /// Create the parts of a working copy database that are read
+    fn create(root: &Path) -> Connection {
+        fs::create_dir_all(root.join(".svn").join("pristine")).unwrap();
+        let db = Connection::open(root.join(".svn").join("wc.db")).unwrap();
+        db.execute_batch(
+            "CREATE TABLE nodes (
+                wc_id INTEGER, local_relpath TEXT, op_depth INTEGER, repos_path TEXT,
+                presence TEXT, kind TEXT, checksum TEXT, translated_size INTEGER,
+                last_mod_time INTEGER);
+            CREATE TABLE actual_node (
+                wc_id INTEGER, local_relpath TEXT, properties BLOB, conflict_data BLOB);
+            INSERT INTO nodes VALUES (1, '', 0, 'branches/feature', 'normal', 'dir', NULL, NULL,
+                NULL);",
+        )
+        .unwrap();
+        db
+    }
+
+    /// Check out a file with `contents`, recording it like svn does
+    fn checkout(root: &Path, db: &Connection, name: &str, contents: &str) {
+        let sha = format!("{:040x}", name.len());
+        let pristine = root.join(".svn").join("pristine").join(&sha[..2]);
+        fs::create_dir_all(&pristine).unwrap();
+        fs::write(pristine.join(format!("{sha}.svn-base")), contents).unwrap();
+        fs::write(root.join(name), contents).unwrap();
+        let meta = fs::metadata(root.join(name)).unwrap();
+        let mtime = meta.modified().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        db.execute(
+            "INSERT INTO nodes VALUES (1, ?1, 0, ?1, 'normal', 'file', ?2, ?3, ?4)",
+            params![
+                name,
+                format!("$sha1${sha}"),
+                i64::try_from(meta.len()).unwrap(),
+                i64::try_from(mtime.as_micros()).unwrap(),
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn working_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("wc");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir(root.join(".svn")).unwrap();
+        assert!(Repo::discover(&root.join("sub")).is_none());
+
+        let db = create(&root);
+        let repo = Repo::discover(&root.join("sub")).unwrap();
+        assert_eq!(repo.root(), root);
+        assert_eq!(repo.branch().as_deref(), Some("branches/feature"));
+        assert_eq!(repo.status(), (false, false, false));
+
+        checkout(&root, &db, "file", "contents");
+        assert_eq!(repo.status(), (false, false, false));
+        // NOTE touching a file without changing it isn't a modification
+        fs::write(root.join("file"), "contents").unwrap();
+        db.execute("UPDATE nodes SET last_mod_time = 0", [])
+            .unwrap();
+        assert_eq!(repo.status(), (false, false, false));
+        fs::write(root.join("file"), "modified").unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        fs::write(root.join("file"), "changed size").unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        fs::remove_file(root.join("file")).unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        fs::write(root.join("file"), "contents").unwrap();
+        assert_eq!(repo.status(), (false, false, false));
+
+        db.execute(
+            "INSERT INTO nodes VALUES (1, 'added', 1, NULL, 'normal', 'file', NULL, NULL, NULL)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+        db.execute("DELETE FROM nodes WHERE op_depth > 0", [])
+            .unwrap();
+        db.execute(
+            "INSERT INTO actual_node VALUES (1, 'file', x'00', NULL)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(repo.status(), (true, true, false));
+    }
+}
@@ -1,4 +1,7 @@
 //! Utilities for prompt expansion
+use std::collections::HashSet;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
 
 /// A parsed SCP style url
 ///
@@ -6,28 +9,25 @@
 /// `<username>@<host>:<path>` and can be specified via the regex `^([^@:]*)@([^@:]*):([^@:]*)$`
 #[derive(Debug, PartialEq, Eq)]
 pub struct ParsedScpUrl<'a> {
-    raw_username: &'a str,
-    raw_host: &'a str,
-    raw_path: &'a str,
+    username: &'a str,
+    host: &'a str,
+    path: &'a str,
 }
 
 impl<'a> ParsedScpUrl<'a> {
     /// Parse an SCP style url
     #[must_use]
     pub fn parse(raw: &'a str) -> Option<Self> {
-        let (raw_username, rest) = raw.split_once('@')?;
-        let (raw_host, raw_path) = rest.split_once(':')?;
-        if raw_username.contains(':')
-            || raw_host.contains('@')
-            || raw_path.contains(':')
-            || raw_path.contains('@')
+        let (username, rest) = raw.split_once('@')?;
+        let (host, path) = rest.split_once(':')?;
+        if username.contains(':') || host.contains('@') || path.contains(':') || path.contains('@')
         {
             None
         } else {
             Some(ParsedScpUrl {
-                raw_username,
-                raw_host,
-                raw_path,
+                username,
+                host,
+                path,
             })
         }
     }
@@ -35,22 +35,394 @@ impl<'a> ParsedScpUrl<'a> {
     /// Get the user name
     #[must_use]
     pub fn username(&self) -> &'a str {
-        self.raw_username
+        self.username
     }
 
     /// Get the host
     #[must_use]
     pub fn host(&self) -> &'a str {
-        self.raw_host
+        self.host
     }
 
     /// Get the path
     #[must_use]
     pub fn path(&self) -> &'a str {
-        self.raw_path
+        self.path
     }
 }
 
+/// A glob pattern that matches a prefix of a path
+///
+/// Patterns are matched one path component at a time. Within a component `*` matches any run of
+/// characters and `?` matches exactly one, while a component that is only `**` matches any number
+/// of whole components. Every wildcard is a capture, numbered from one in the order they appear.
+/// Any character can be escaped with a backslash, so a pattern without unescaped wildcards
+/// behaves like [`Path::strip_prefix`]. A [literal](PathPattern::literal) pattern has no
+/// wildcards or escapes at all.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PathPattern {
+    parts: Vec<PatternPart>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PatternPart {
+    Root,
+    Components,
+    Component(Vec<PatternToken>),
+}
+
+type VarLookup<'l> = dyn FnMut(&str) -> Option<String> + 'l;
+
+#[derive(Debug, PartialEq, Eq)]
+enum PatternLexeme {
+    Sep,
+    Token(PatternToken),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PatternToken {
+    Char(char),
+    Any,
+    One,
+}
+
+impl PathPattern {
+    /// Parse a glob pattern
+    #[must_use]
+    pub fn parse(raw: &str) -> Self {
+        // NOTE lexing can only fail when expanding variables
+        PathPattern::from_lexemes(PathPattern::lex(raw, true, None).unwrap_or_default())
+    }
+
+    /// Parse a pattern where every character is matched literally, like [`Path::strip_prefix`]
+    #[must_use]
+    pub fn literal(raw: &str) -> Self {
+        PathPattern::from_lexemes(PathPattern::lex(raw, false, None).unwrap_or_default())
+    }
+
+    /// Parse a path pattern while expanding variables, with wildcards if `glob` is set
+    ///
+    /// `$NAME` and `${NAME}` are replaced with the result of `lookup`, and a leading `~` is
+    /// replaced with the value of `HOME`. Expanded text is matched literally, except that `/`
+    /// still separates components. Returns `None` if any referenced variable is undefined.
+    pub fn parse_expanding(
+        raw: &str,
+        glob: bool,
+        mut lookup: impl FnMut(&str) -> Option<String>,
+    ) -> Option<Self> {
+        Some(PathPattern::from_lexemes(PathPattern::lex(
+            raw,
+            glob,
+            Some(&mut lookup),
+        )?))
+    }
+
+    fn lex(
+        raw: &str,
+        glob: bool,
+        mut lookup: Option<&mut VarLookup<'_>>,
+    ) -> Option<Vec<PatternLexeme>> {
+        // NOTE backslashes only escape when there's something special to escape
+        let escapes = glob || lookup.is_some();
+        let mut lexemes = Vec::new();
+        let mut chars = raw.chars().peekable();
+        while let Some(chr) = chars.next() {
+            let expanded = match (chr, &mut lookup) {
+                ('\\', _) if escapes => {
+                    lexemes.push(PatternLexeme::Token(PatternToken::Char(
+                        chars.next().unwrap_or('\\'),
+                    )));
+                    continue;
+                }
+                ('~', Some(lookup))
+                    if lexemes.is_empty() && matches!(chars.peek(), None | Some('/')) =>
+                {
+                    lookup("HOME")?
+                }
+                ('$', Some(lookup)) => {
+                    let braced = chars.next_if_eq(&'{').is_some();
+                    let mut name = String::new();
+                    while let Some(next) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    {
+                        name.push(next);
+                    }
+                    if name.is_empty() || braced && chars.next_if_eq(&'}').is_none() {
+                        // NOTE not a valid expansion, so treat everything literally
+                        let literal = if braced { "${" } else { "$" };
+                        lexemes.extend(
+                            literal
+                                .chars()
+                                .chain(name.chars())
+                                .map(|chr| PatternLexeme::Token(PatternToken::Char(chr))),
+                        );
+                        continue;
+                    }
+                    lookup(&name)?
+                }
+                (chr, _) => {
+                    lexemes.push(match chr {
+                        '*' if glob => PatternLexeme::Token(PatternToken::Any),
+                        '?' if glob => PatternLexeme::Token(PatternToken::One),
+                        '/' => PatternLexeme::Sep,
+                        chr => PatternLexeme::Token(PatternToken::Char(chr)),
+                    });
+                    continue;
+                }
+            };
+            lexemes.extend(expanded.chars().map(|chr| match chr {
+                '/' => PatternLexeme::Sep,
+                chr => PatternLexeme::Token(PatternToken::Char(chr)),
+            }));
+        }
+        Some(lexemes)
+    }
+
+    fn from_lexemes(lexemes: Vec<PatternLexeme>) -> Self {
+        let mut parts = Vec::new();
+        if lexemes.first() == Some(&PatternLexeme::Sep) {
+            parts.push(PatternPart::Root);
+        }
+        let mut component = Vec::new();
+        for lexeme in lexemes {
+            match lexeme {
+                PatternLexeme::Sep => {
+                    PathPattern::push_component(&mut parts, mem::take(&mut component));
+                }
+                PatternLexeme::Token(token) => component.push(token),
+            }
+        }
+        PathPattern::push_component(&mut parts, component);
+        PathPattern { parts }
+    }
+
+    fn push_component(parts: &mut Vec<PatternPart>, tokens: Vec<PatternToken>) {
+        // NOTE this mirrors how `Path::components` normalizes repeated separators and `.`
+        match tokens.as_slice() {
+            [] => (),
+            [PatternToken::Char('.')] if !parts.is_empty() => (),
+            [PatternToken::Any, PatternToken::Any] => parts.push(PatternPart::Components),
+            _ => parts.push(PatternPart::Component(tokens)),
+        }
+    }
+
+    /// Match this pattern against the start of `path`
+    ///
+    /// Returns `None` if the pattern doesn't match any prefix of `path`. Wildcards are greedy, so
+    /// when several prefixes match, the one with the earliest and longest captures is chosen.
+    #[must_use]
+    pub fn strip_prefix<'p>(&self, path: &'p Path) -> Option<PrefixMatch<'p>> {
+        let comps: Vec<_> = path.components().collect();
+        let mut captures = Vec::new();
+        let consumed =
+            PathPattern::match_parts(&self.parts, &comps, &mut captures, &mut HashSet::new())?;
+        let mut rest = path.components();
+        for _ in 0..consumed {
+            rest.next();
+        }
+        Some(PrefixMatch {
+            captures,
+            rest: rest.as_path(),
+        })
+    }
+
+    /// Match `parts` against the start of `comps`, returning how many components were consumed
+    ///
+    /// Whether the rest of a pattern matches the rest of a path doesn't depend on what was
+    /// captured before, so the suffixes that failed are remembered in `failed`, which keeps
+    /// patterns with several `**` from backtracking exponentially.
+    fn match_parts(
+        parts: &[PatternPart],
+        comps: &[Component],
+        captures: &mut Vec<String>,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> Option<usize> {
+        let key = (parts.len(), comps.len());
+        if failed.contains(&key) {
+            return None;
+        }
+        let consumed = PathPattern::match_parts_uncached(parts, comps, captures, failed);
+        if consumed.is_none() {
+            failed.insert(key);
+        }
+        consumed
+    }
+
+    fn match_parts_uncached(
+        parts: &[PatternPart],
+        comps: &[Component],
+        captures: &mut Vec<String>,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> Option<usize> {
+        match parts.split_first() {
+            None => Some(0),
+            Some((PatternPart::Root, rest)) => match comps.split_first() {
+                Some((Component::RootDir, tail)) => {
+                    Some(PathPattern::match_parts(rest, tail, captures, failed)? + 1)
+                }
+                _ => None,
+            },
+            Some((PatternPart::Components, rest)) => (0..=comps.len()).rev().find_map(|num| {
+                let joined: PathBuf = comps[..num].iter().collect();
+                captures.push(joined.to_string_lossy().into_owned());
+                if let Some(consumed) =
+                    PathPattern::match_parts(rest, &comps[num..], captures, failed)
+                {
+                    Some(consumed + num)
+                } else {
+                    captures.pop();
+                    None
+                }
+            }),
+            Some((PatternPart::Component(tokens), rest)) => {
+                let (comp, tail) = comps.split_first()?;
+                if matches!(comp, Component::RootDir) {
+                    return None;
+                }
+                let chars: Vec<_> = comp.as_os_str().to_string_lossy().chars().collect();
+                let len = captures.len();
+                if PathPattern::match_tokens(tokens, &chars, captures, &mut HashSet::new()) {
+                    if let Some(consumed) = PathPattern::match_parts(rest, tail, captures, failed) {
+                        return Some(consumed + 1);
+                    }
+                }
+                captures.truncate(len);
+                None
+            }
+        }
+    }
+
+    /// Match `tokens` against all of `chars`, remembering failures like [`Self::match_parts`]
+    fn match_tokens(
+        tokens: &[PatternToken],
+        chars: &[char],
+        captures: &mut Vec<String>,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> bool {
+        let key = (tokens.len(), chars.len());
+        if failed.contains(&key) {
+            return false;
+        }
+        let matched = PathPattern::match_tokens_uncached(tokens, chars, captures, failed);
+        if !matched {
+            failed.insert(key);
+        }
+        matched
+    }
+
+    fn match_tokens_uncached(
+        tokens: &[PatternToken],
+        chars: &[char],
+        captures: &mut Vec<String>,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> bool {
+        match tokens.split_first() {
+            None => chars.is_empty(),
+            Some((PatternToken::Char(chr), rest)) => match chars.split_first() {
+                Some((first, tail)) if first == chr => {
+                    PathPattern::match_tokens(rest, tail, captures, failed)
+                }
+                _ => false,
+            },
+            Some((PatternToken::One, rest)) => match chars.split_first() {
+                Some((first, tail)) => {
+                    captures.push(first.to_string());
+                    PathPattern::match_tokens(rest, tail, captures, failed) || {
+                        captures.pop();
+                        false
+                    }
+                }
+                None => false,
+            },
+            Some((PatternToken::Any, rest)) => (0..=chars.len()).rev().any(|num| {
+                captures.push(chars[..num].iter().collect());
+                PathPattern::match_tokens(rest, &chars[num..], captures, failed) || {
+                    captures.pop();
+                    false
+                }
+            }),
+        }
+    }
+}
+
+/// A successful match of a [`PathPattern`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrefixMatch<'p> {
+    captures: Vec<String>,
+    rest: &'p Path,
+}
+
+impl<'p> PrefixMatch<'p> {
+    /// Get the captured text of every wildcard in order
+    #[must_use]
+    pub fn captures(&self) -> &[String] {
+        &self.captures
+    }
+
+    /// Get the remainder of the path after the matched prefix
+    #[must_use]
+    pub fn rest(&self) -> &'p Path {
+        self.rest
+    }
+
+    /// Expand a replacement string using the captures of this match
+    ///
+    /// `\1` through `\9` are replaced with the corresponding capture, or nothing if there aren't
+    /// that many captures, and any other character preceded by a backslash is included literally.
+    #[must_use]
+    pub fn expand(&self, replacement: &str) -> String {
+        let mut result = String::with_capacity(replacement.len());
+        let mut chars = replacement.chars();
+        while let Some(chr) = chars.next() {
+            if chr != '\\' {
+                result.push(chr);
+                continue;
+            }
+            match chars.next() {
+                Some(num @ '1'..='9') => {
+                    let ind = num as usize - '1' as usize;
+                    if let Some(capture) = self.captures.get(ind) {
+                        result.push_str(capture);
+                    }
+                }
+                Some(escaped) => result.push(escaped),
+                None => result.push('\\'),
+            }
+        }
+        result
+    }
+}
+
+/// Parse zsh named directories from the output of `hash -d`
+///
+/// Each line should have the form `name=directory`, where the directory may be quoted the way zsh
+/// quotes it, with single quotes or backslashes. Lines that don't match are ignored.
+#[must_use]
+pub fn parse_named_dirs(raw: &str) -> Vec<(String, PathBuf)> {
+    raw.lines()
+        .filter_map(|line| {
+            let (name, quoted) = line.split_once('=')?;
+            if name.is_empty() {
+                return None;
+            }
+            let mut dir = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(chr) = chars.next() {
+                match chr {
+                    '\\' => dir.push(chars.next()?),
+                    '\'' => loop {
+                        match chars.next()? {
+                            '\'' => break,
+                            chr => dir.push(chr),
+                        }
+                    },
+                    chr => dir.push(chr),
+                }
+            }
+            Some((name.to_owned(), dir.into()))
+        })
+        .collect()
+}
+
 /// Generic trait for anything that "has" chars
 ///
 /// This is similar to the [`Pattern`][std::str::pattern::Pattern] trait, except that that's still
@@ -93,7 +465,8 @@ impl<T: ContainsChar> ContainsChar for &T {
 
 #[cfg(test)]
 mod tests {
-    use super::ParsedScpUrl;
+    use super::{parse_named_dirs, ParsedScpUrl, PathPattern};
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn git_standard() {
@@ -111,6 +484,104 @@ mod tests {
         assert_eq!(parsed.path(), "");
     }
 
+    #[test]
+    fn literal_pattern() {
+        let pattern = PathPattern::parse("/home/user/");
+        let matched = pattern.strip_prefix(Path::new("/home/user/sub")).unwrap();
+        assert_eq!(matched.rest(), Path::new("sub"));
+        assert!(matched.captures().is_empty());
+        assert_eq!(pattern.strip_prefix(Path::new("/home/username")), None);
+        assert_eq!(pattern.strip_prefix(Path::new("home/user")), None);
+
+        let matched = PathPattern::parse("")
+            .strip_prefix(Path::new("/a"))
+            .unwrap();
+        assert_eq!(matched.rest(), Path::new("/a"));
+    }
+
+    #[test]
+    fn glob_pattern() {
+        let pattern = PathPattern::parse("/src/*/s?c/*");
+        let matched = pattern
+            .strip_prefix(Path::new("/src/team/svc/api/bin"))
+            .unwrap();
+        assert_eq!(matched.captures(), ["team", "v", "api"]);
+        assert_eq!(matched.rest(), Path::new("bin"));
+        assert_eq!(matched.expand("\\1\\:\\3\\4\\\\"), "team:api\\");
+
+        let pattern = PathPattern::parse("/**/b/*.rs");
+        let matched = pattern.strip_prefix(Path::new("/a/b/c/b/d.rs/e")).unwrap();
+        assert_eq!(matched.captures(), ["a/b/c", "d"]);
+        assert_eq!(matched.rest(), Path::new("e"));
+
+        let pattern = PathPattern::parse("/a/\\*");
+        assert_eq!(pattern.strip_prefix(Path::new("/a/b")), None);
+        assert!(pattern.strip_prefix(Path::new("/a/*")).is_some());
+    }
+
+    #[test]
+    fn expanding_pattern() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/user".to_owned()),
+            "STAR" => Some("*".to_owned()),
+            _ => None,
+        };
+        let path = Path::new("/home/user/*/dir");
+
+        let pattern = PathPattern::parse_expanding("~/$STAR", true, lookup).unwrap();
+        assert_eq!(pattern.strip_prefix(path).unwrap().rest(), Path::new("dir"));
+        assert_eq!(pattern.strip_prefix(Path::new("/home/user/a")), None);
+
+        let pattern = PathPattern::parse_expanding("${HOME}/*", true, lookup).unwrap();
+        assert_eq!(pattern.strip_prefix(path).unwrap().captures(), ["*"]);
+
+        assert_eq!(PathPattern::parse_expanding("$MISSING", true, lookup), None);
+        assert_eq!(
+            PathPattern::parse_expanding("\\~/$/${HOME", true, lookup),
+            Some(PathPattern::parse("\\~/\\$/\\${HOME"))
+        );
+        assert_eq!(
+            PathPattern::parse_expanding("/a~", true, lookup),
+            Some(PathPattern::parse("/a~"))
+        );
+        // NOTE without globbing only the expansions can be escaped
+        let pattern = PathPattern::parse_expanding("~/*", false, lookup).unwrap();
+        assert_eq!(pattern.strip_prefix(path).unwrap().rest(), Path::new("dir"));
+        assert_eq!(pattern.strip_prefix(Path::new("/home/user/a")), None);
+    }
+
+    #[test]
+    fn literal_only_pattern() {
+        let pattern = PathPattern::literal("/a/*/b?\\");
+        assert!(pattern.strip_prefix(Path::new("/a/x/bc\\")).is_none());
+        let matched = pattern.strip_prefix(Path::new("/a/*/b?\\/c")).unwrap();
+        assert!(matched.captures().is_empty());
+        assert_eq!(matched.rest(), Path::new("c"));
+    }
+
+    #[test]
+    fn many_wildcards() {
+        // NOTE without remembering failures this would try every way to split the path
+        let pattern = PathPattern::parse(&format!("{}x*y", "**/*a*/".repeat(12)));
+        let path: PathBuf = ["/"].into_iter().chain(["aaaa"; 40]).collect();
+        assert_eq!(pattern.strip_prefix(&path), None);
+    }
+
+    #[test]
+    fn named_dirs() {
+        let parsed = parse_named_dirs(
+            "proj=/long/path\nspaced='/a dir/it'\\''s'\nescaped=/b\\ dir\n\nbad\n=/x\n",
+        );
+        assert_eq!(
+            parsed,
+            [
+                ("proj".to_owned(), PathBuf::from("/long/path")),
+                ("spaced".to_owned(), PathBuf::from("/a dir/it's")),
+                ("escaped".to_owned(), PathBuf::from("/b dir")),
+            ]
+        );
+    }
+
     #[test]
     fn parse_errors() {
         assert_eq!(ParsedScpUrl::parse(""), None);